@@ -4,113 +4,190 @@ extern crate serde;
 
 extern crate serde_json;
 use serde_json::ser::to_string;
+use serde_json::de::from_str;
 
 extern crate itertools;
 use itertools::Itertools;
 
 extern crate zmq;
 
-use std::env::args;
+use std::env::{self, args, current_dir};
+use std::process::exit;
 use std::path::*;
 
 #[path = "../shared/ipc.rs"]
 mod ipc;
 
+mod repl;
+
 fn main() {
     // Get zmq context
     let mut context = zmq::Context::new();
 
-    // Open response socket for bidirectional operation
-    let mut socket = match context.socket(zmq::PAIR) {
+    // Make vector of strings
+    let mut v = args().skip(1).map(|s| s.to_string()).collect_vec();
+
+    // Take the options off the front
+    let mut json = false;
+    let mut endpoint = None;
+    let mut token = env::var(ipc::TOKEN_VAR).ok();
+    while v.get(0).map(|s| s.starts_with("--")).unwrap_or(false) {
+        match v.remove(0).as_str() {
+            // Print the replies exactly as they come for scripts to read
+            "--json" => json = true,
+            "--endpoint" => {
+                if v.is_empty() {
+                    panic!("Error: --endpoint needs an endpoint like tcp://127.0.0.1:2934");
+                }
+                endpoint = Some(v.remove(0));
+            },
+            "--token" => {
+                if v.is_empty() {
+                    panic!("Error: --token needs a token");
+                }
+                token = Some(v.remove(0));
+            },
+            o => panic!("Error: Unrecognized option `{}`", o),
+        }
+    }
+    let endpoint = ipc::endpoint(endpoint, None);
+
+    // Open dealer socket for bidirectional operation; the server routes replies back to this socket
+    let mut socket = match context.socket(zmq::DEALER) {
         Ok(s) => s,
         Err(e) => panic!("Error: Failed to open zmq socket: {}", e),
     };
 
-    // Connect to the file
-    match socket.connect(ipc::PATH) {
+    // Connect to the endpoint
+    match socket.connect(&endpoint) {
         Ok(_) => {},
-        Err(e) => panic!("Error: Failed to connect to ipc file: {}", e),
+        Err(e) => panic!("Error: Failed to connect to `{}`: {}", endpoint, e),
     }
 
-    // Make vector of strings
-    let mut v = args().skip(1).map(|s| s.to_string()).collect_vec();
+    // Check the token up front so a bad one is reported once rather than by every command
+    if let Some(ref token) = token {
+        if !authenticate(&mut socket, token) {
+            exit(1);
+        }
+    }
 
-    match v.len() {
-        0 => {},
-        _ => {
-            match v[0].as_str() {
-                "import" => {
-                    match v.len() {
-                        1 => {}
-                        _ => {
-                            match v[1].as_str() {
-                                "lines" => {
-                                    if v.len() == 3 {
-                                        let new_path = Path::new(&v[2])
-                                            .canonicalize()
-                                            .unwrap_or_else(|e| panic!("Error: Unable to canonicalize path: {}", e))
-                                            .to_str()
-                                            .unwrap_or_else(|| panic!("Error: Failed to convert path to string"))
-                                            .to_string();
-                                        v[2] = new_path;
-                                    }
-                                },
-                                _ => {}
-                            }
-                        },
-                    }
-                },
-                "connect" => {
-                    match v.len() {
-                        1 => {}
-                        _ => {
-                            match v[1].as_str() {
-                                "irc" => {
-                                    if v.len() == 3 {
-                                        let new_path = Path::new(&v[2])
-                                            .canonicalize()
-                                            .unwrap_or_else(|e| panic!("Error: Unable to canonicalize path: {}", e))
-                                            .to_str()
-                                            .unwrap_or_else(|| panic!("Error: Failed to convert path to string"))
-                                            .to_string();
-                                        v[2] = new_path;
-                                    }
-                                },
-                                "discord" => {
-                                    if v.len() == 3 {
-                                        let new_path = Path::new(&v[2])
-                                            .canonicalize()
-                                            .unwrap_or_else(|e| panic!("Error: Unable to canonicalize path: {}", e))
-                                            .to_str()
-                                            .unwrap_or_else(|| panic!("Error: Failed to convert path to string"))
-                                            .to_string();
-                                        v[2] = new_path;
-                                    }
-                                },
-                                _ => {}
-                            }
-                        },
-                    }
-                },
-                _ => {},
+    // With nothing to send, stay connected and take commands interactively
+    if v.is_empty() || (v.len() == 1 && v[0] == "-i") {
+        repl::run(&mut socket, token, json);
+        return;
+    }
+
+    prepare(&mut v).unwrap_or_else(|e| panic!("Error: {}", e));
+    if !command(&mut socket, token.as_ref().map(|t| t.as_str()), &v, json) {
+        exit(1);
+    }
+}
+
+/// Paths are resolved by seifmios, which may be running in a different directory, so make them absolute first.
+pub fn prepare(v: &mut Vec<String>) -> Result<(), String> {
+    let index = match (v.get(0).map(|s| s.as_str()), v.get(1).map(|s| s.as_str())) {
+        (Some("import"), Some("lines")) |
+        (Some("import"), Some("discord-export")) |
+        (Some("import"), Some("jsonl")) |
+        (Some("connect"), Some("irc")) |
+        (Some("connect"), Some("discord")) => 2,
+        (Some("import"), Some("irclog")) => 3,
+        (Some("connect"), Some("server")) => {
+            // Unix socket paths are resolved by seifmios too
+            if v.len() >= 3 && v[2].starts_with("unix:") {
+                let new_path = try!(current_dir().map_err(|e| format!("Unable to get current directory: {}", e)))
+                    .join(&v[2]["unix:".len()..])
+                    .to_str()
+                    .map(|s| format!("unix:{}", s));
+                v[2] = try!(new_path.ok_or_else(|| "Failed to convert path to string".to_string()));
+            }
+            return Ok(());
+        },
+        (Some("save"), Some(_)) => {
+            let new_path = try!(current_dir().map_err(|e| format!("Unable to get current directory: {}", e)))
+                .join(&v[1])
+                .to_str()
+                .map(|s| s.to_string());
+            v[1] = try!(new_path.ok_or_else(|| "Failed to convert path to string".to_string()));
+            return Ok(());
+        },
+        (Some("export"), Some(_)) => {
+            // The file doesn't exist yet, so it can't be canonicalized; make it absolute instead
+            if v.len() >= 3 {
+                let new_path = try!(current_dir().map_err(|e| format!("Unable to get current directory: {}", e)))
+                    .join(&v[2])
+                    .to_str()
+                    .map(|s| s.to_string());
+                v[2] = try!(new_path.ok_or_else(|| "Failed to convert path to string".to_string()));
             }
+            return Ok(());
         },
+        _ => return Ok(()),
+    };
+    if v.len() > index {
+        let new_path = try!(Path::new(&v[index])
+            .canonicalize()
+            .map_err(|e| format!("Unable to canonicalize path: {}", e)))
+            .to_str()
+            .map(|s| s.to_string());
+        v[index] = try!(new_path.ok_or_else(|| "Failed to convert path to string".to_string()));
     }
+    Ok(())
+}
 
+/// Send a command and print each part of the response as it arrives. Returns false if the command failed.
+pub fn command(socket: &mut zmq::Socket, token: Option<&str>, v: &[String], json: bool) -> bool {
+    exchange(socket, token, v, |s, reply| {
+        if json {
+            println!("{}", s);
+        } else if let Some(ref payload) = reply.payload {
+            println!("{}", payload);
+        }
+    })
+}
+
+/// Check that a token is accepted, only saying anything if it isn't.
+fn authenticate(socket: &mut zmq::Socket, token: &str) -> bool {
+    exchange(socket, None, &["auth".to_string(), token.to_string()], |_, reply| {
+        if reply.status == ipc::STATUS_ERROR {
+            if let Some(ref payload) = reply.payload {
+                println!("{}", payload);
+            }
+        }
+    })
+}
+
+/// Send a command, with the token in the frame before it if there is one, and give every reply to `f` until the
+/// response ends. Returns false if the command failed.
+fn exchange<F>(socket: &mut zmq::Socket, token: Option<&str>, v: &[String], mut f: F) -> bool
+    where F: FnMut(&str, &ipc::Reply)
+{
     // Aquire JSON vector string from args
     let s = match to_string(&v) {
         Ok(s) => s,
         Err(e) => panic!("Error: JSON parsing failure: {}", e),
     };
 
+    if let Some(token) = token {
+        socket.send_str(token, zmq::SNDMORE).unwrap_or_else(|e| panic!("Error: Unable to send token: {}", e));
+    }
     socket.send_str(&s, 0).unwrap_or_else(|e| panic!("Error: Unable to send JSON command vector: {}", e));
 
     loop {
         match socket.recv_string(0) {
             Ok(m) => match m {
-                Ok(s) => match s.as_str() {
-                    "" => break,
-                    s => println!("{}", s),
+                Ok(s) => {
+                    let reply = match from_str::<ipc::Reply>(&s) {
+                        Ok(r) => r,
+                        Err(e) => {
+                            println!("Warning: Got invalid reply: {}", e);
+                            continue;
+                        },
+                    };
+                    f(&s, &reply);
+                    if reply.status == ipc::STATUS_END {
+                        return reply.code.is_none();
+                    }
                 },
                 Err(_) => println!("Warning: Got invalid string message"),
             },
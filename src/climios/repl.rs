@@ -0,0 +1,173 @@
+extern crate rustyline;
+use self::rustyline::Editor;
+use self::rustyline::completion::{Completer, FilenameCompleter};
+use self::rustyline::error::ReadlineError;
+
+extern crate zmq;
+
+use std::env;
+use std::path::PathBuf;
+
+const PROMPT: &'static str = "seifmios> ";
+const HISTORY_FILE: &'static str = ".climios_history";
+
+const COMMANDS: &'static [&'static str] = &[
+    "help", "auth", "quit", "import", "jobs", "cancel", "export", "connect", "connections",
+    "disconnect", "reconnect", "list", "stats", "save", "reload", "preset", "respond", "tell",
+    "forget", "get", "set", "find", "exit",
+];
+const VALUES: &'static [&'static str] = &["cc_ratio", "cc_travel", "cc_mag", "fw_edge", "bw_edge", "fw_word", "bw_word"];
+const GET_VALUES: &'static [&'static str] = &[
+    "all", "cc_ratio", "cc_travel", "cc_mag", "fw_edge", "bw_edge", "fw_word", "bw_word",
+];
+const IMPORT_TYPES: &'static [&'static str] = &["lines", "irclog", "discord-export", "jsonl"];
+const IRCLOG_FORMATS: &'static [&'static str] = &["irssi", "weechat", "znc"];
+const EXPORT_TYPES: &'static [&'static str] = &["graph", "messages"];
+const CONNECT_TYPES: &'static [&'static str] = &["server", "http", "websocket", "irc", "discord"];
+const LIST_TYPES: &'static [&'static str] = &["categories"];
+const PRESET_ACTIONS: &'static [&'static str] = &["save", "load", "list"];
+const FIND_TYPES: &'static [&'static str] = &["relation", "word"];
+const FORGET_TYPES: &'static [&'static str] = &["last"];
+const FORGET_MODES: &'static [&'static str] = &["remove"];
+
+/// Completes words from the command tree, falling back to file names for arguments.
+struct CommandCompleter {
+    files: FilenameCompleter,
+}
+
+impl CommandCompleter {
+    /// Get the choices for the word at `index` given the words before it
+    fn choices(words: &[String], index: usize) -> Option<&'static [&'static str]> {
+        match index {
+            0 => Some(COMMANDS),
+            1 => match words[0].as_str() {
+                "set" => Some(VALUES),
+                "get" => Some(GET_VALUES),
+                "import" => Some(IMPORT_TYPES),
+                "export" => Some(EXPORT_TYPES),
+                "connect" => Some(CONNECT_TYPES),
+                "list" => Some(LIST_TYPES),
+                "find" => Some(FIND_TYPES),
+                "preset" => Some(PRESET_ACTIONS),
+                "forget" => Some(FORGET_TYPES),
+                _ => None,
+            },
+            2 => match (words[0].as_str(), words[1].as_str()) {
+                ("import", "irclog") => Some(IRCLOG_FORMATS),
+                ("forget", "last") => Some(FORGET_MODES),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Completer for CommandCompleter {
+    fn complete(&self, line: &str, pos: usize) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        // Start of the word being completed
+        let start = before.rfind(' ').map(|i| i + 1).unwrap_or(0);
+        let words = split(&before[..start]);
+        match Self::choices(&words, words.len()) {
+            Some(choices) => {
+                let word = &before[start..];
+                Ok((start, choices.iter().filter(|c| c.starts_with(word)).map(|c| c.to_string()).collect()))
+            },
+            None => self.files.complete(line, pos),
+        }
+    }
+}
+
+/// Split a line into words like a shell would, so `tell "hello there"` is two words.
+pub fn split(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '"') | (None, '\'') => {
+                quote = Some(c);
+                in_word = true;
+            },
+            (_, '\\') => {
+                if let Some(escaped) = chars.next() {
+                    word.push(escaped);
+                }
+                in_word = true;
+            },
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(word);
+                    word = String::new();
+                    in_word = false;
+                }
+            },
+            (_, c) => {
+                word.push(c);
+                in_word = true;
+            },
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    words
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::home_dir().map(|home| home.join(HISTORY_FILE))
+}
+
+/// Read commands until the user leaves, sending each one over the same connection. A token accepted by `auth` is
+/// sent with every command after it.
+pub fn run(socket: &mut zmq::Socket, mut token: Option<String>, json: bool) {
+    let mut editor = Editor::new();
+    editor.set_completer(Some(CommandCompleter{
+        files: FilenameCompleter::new(),
+    }));
+    if let Some(path) = history_path() {
+        // There won't be any history the first time
+        let _ = editor.load_history(&path);
+    }
+
+    loop {
+        match editor.readline(PROMPT) {
+            Ok(line) => {
+                let mut v = split(&line);
+                if v.is_empty() {
+                    continue;
+                }
+                editor.add_history_entry(&line);
+                if v[0] == "exit" {
+                    break;
+                }
+                match super::prepare(&mut v) {
+                    Ok(_) => {
+                        let ok = super::command(socket, token.as_ref().map(|t| t.as_str()), &v, json);
+                        if ok && v[0] == "auth" && v.len() == 2 {
+                            token = Some(v[1].clone());
+                        }
+                    },
+                    Err(e) => println!("Ignored: {}", e),
+                }
+                if v[0] == "quit" && v.len() == 1 {
+                    break;
+                }
+            },
+            Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                println!("Error: Unable to read line: {}", e);
+                break;
+            },
+        }
+    }
+
+    if let Some(path) = history_path() {
+        editor.save_history(&path).unwrap_or_else(|e| {
+            println!("Warning: Unable to save history: {}", e);
+        });
+    }
+}
@@ -2,12 +2,12 @@ extern crate discord;
 extern crate serde_json;
 
 use self::discord::Discord;
-use self::discord::model::Event;
+use self::discord::model::{ChannelId, Event, UserId};
 use std::fs::File;
-use std::sync::mpsc::channel;
-use std::sync::mpsc::Sender;
-use chat::ChatMessage;
-use chat::ReplyMessage;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use chat::{recv_within, AdapterError, ChatAdapter, ChatMessage, Incoming};
 use std::path::Path;
 
 #[derive(Deserialize, Debug)]
@@ -17,54 +17,142 @@ struct Config {
     password: String,
 }
 
-pub fn connect<P>(sender: Sender<ReplyMessage>, path: P)
-    where P: AsRef<Path>
-{
-    let file = File::open(path).unwrap();
-    let config: Config = serde_json::from_reader(file).unwrap();
-    let discord = Discord::new(config.email.as_str(), config.password.as_str()).expect("login failed");
+/// What the adapter waits for
+enum Received {
+    /// Read by the thread of the connection with this number. An old connection's thread can still be reading.
+    Event(usize, Result<Event, String>),
+    /// Stop waiting, so being told to stop is noticed
+    Wake,
+}
 
-    let (mut connection, _) = discord.connect().expect("connect failed");
-    println!("Ready.");
-    loop {
-        match connection.recv_event() {
-            Ok(Event::Closed(n)) => {
-                println!("Discord closed on us with status {}", n);
-                let (new_connection, _) = discord.connect().expect("connect failed");
-                connection = new_connection;
-            }
-            Ok(Event::MessageCreate(message)) => {
-                println!("{} says: {}", message.author.name, message.content);
-                if message.content == "!quit" {
-                    println!("Quitting.");
-                    break
-                }
-                else {
-                    let chat_message = ChatMessage {
-                            source: message.channel_id.0.to_string(),
-                            author: message.author.name,
-                            message: message.content.clone(),
-                        };
-                    if message.content.contains(config.name.as_str()) {
-                        let (reply_sender, reply_reciever) = channel();
-                        sender.send(ReplyMessage(chat_message, Some(reply_sender))).unwrap();
-                        if let Some(reply_string) = reply_reciever.recv().unwrap() {
-                            let _ = discord.send_message(&message.channel_id, &reply_string, "", false);
-                        }
-                    }
-                    else {
-                        sender.send(ReplyMessage(chat_message, None)).unwrap();
-                    }
+pub struct DiscordAdapter {
+    config: Config,
+    // Logged in by connecting and taken when logging out
+    discord: Option<Discord>,
+    /// How many times it has connected, which numbers the current connection
+    connections: usize,
+    events: Receiver<Received>,
+    event_sender: Sender<Received>,
+    /// Who the bot is logged in as, so it doesn't hear itself
+    me: Option<UserId>,
+}
+
+impl DiscordAdapter {
+    pub fn new<P>(path: P) -> Result<DiscordAdapter, AdapterError>
+        where P: AsRef<Path>
+    {
+        let file = try!(File::open(path).map_err(|e| AdapterError::Config(format!("Couldn't open config file: {}", e))));
+        let config: Config = try!(serde_json::from_reader(file)
+            .map_err(|e| AdapterError::Config(format!("Couldn't read config file: {}", e))));
+        let (event_sender, events) = channel();
+        Ok(DiscordAdapter{
+            config: config,
+            discord: None,
+            connections: 0,
+            events: events,
+            event_sender: event_sender,
+            me: None,
+        })
+    }
+}
+
+impl ChatAdapter for DiscordAdapter {
+    fn name(&self) -> &'static str {
+        "Discord"
+    }
+
+    fn nick(&self) -> Option<String> {
+        Some(self.config.name.clone())
+    }
+
+    fn connect(&mut self) -> Result<(), AdapterError> {
+        // Logging in needs the network too, so it is retried along with connecting
+        if self.discord.is_none() {
+            self.discord = Some(try!(Discord::new(self.config.email.as_str(), self.config.password.as_str())
+                .map_err(|e| AdapterError::Connect(format!("Login failed: {}", e)))));
+        }
+        let discord = self.discord.as_ref().unwrap();
+        let (mut connection, ready) = try!(discord.connect()
+            .map_err(|e| AdapterError::Connect(format!("Connect failed: {}", e))));
+        self.me = Some(ready.user.id);
+        println!("Ready.");
+        // Reading blocks, so it is done in its own thread, which ends with the connection or once nobody listens
+        self.connections += 1;
+        let (sender, number) = (self.event_sender.clone(), self.connections);
+        thread::spawn(move || {
+            loop {
+                let event = connection.recv_event().map_err(|e| format!("{:?}", e));
+                let ended = match event {
+                    Ok(Event::Closed(_)) | Err(_) => true,
+                    _ => false,
+                };
+                if sender.send(Received::Event(number, event)).is_err() || ended {
+                    break;
                 }
             }
-            Ok(_) => {}
-            Err(err) => {
-                println!("Receive error: {:?}", err);
-                let (new_connection, _) = discord.connect().expect("connect failed");
-                connection = new_connection;
-            }
+        });
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Option<Duration>) -> Result<Option<Incoming>, AdapterError> {
+        if self.connections == 0 {
+            return Err(AdapterError::Disconnected("Not connected".to_string()));
+        }
+        let event = match try!(recv_within(&self.events, timeout)
+            .map_err(|_| AdapterError::Disconnected("Connection ended".to_string())))
+        {
+            Some(Received::Event(number, event)) => {
+                // Left over from an old connection
+                if number != self.connections {
+                    return Ok(None);
+                }
+                try!(event.map_err(|e| AdapterError::Disconnected(format!("Receive error: {}", e))))
+            },
+            Some(Received::Wake) | None => return Ok(None),
+        };
+        match event {
+            Event::Closed(n) => Err(AdapterError::Disconnected(format!("Discord closed on us with status {}", n))),
+            // Replying to itself would never end, and its own messages aren't anyone speaking
+            Event::MessageCreate(ref message) if Some(message.author.id) == self.me => Ok(None),
+            Event::MessageCreate(message) => {
+                println!("{} says: {}", message.author.name, message.content);
+                Ok(Some(Incoming{
+                    message: ChatMessage{
+                        source: message.channel_id.0.to_string(),
+                        author: message.author.name,
+                        message: message.content,
+                    },
+                    target: message.channel_id.0.to_string(),
+                    wants_reply: false,
+                    identity: Some(message.author.id.0.to_string()),
+                }))
+            },
+            _ => Ok(None),
         }
     }
 
-    discord.logout().expect("logout failed");
+    fn send(&mut self, target: &str, reply: Option<&str>) -> Result<(), AdapterError> {
+        let reply = match reply {
+            Some(reply) => reply,
+            None => return Ok(()),
+        };
+        let discord = try!(self.discord.as_ref().ok_or_else(|| AdapterError::Send("Logged out".to_string())));
+        let channel = try!(target.parse().map_err(|e| AdapterError::Send(format!("Invalid channel `{}`: {}", target, e))));
+        discord.send_message(&ChannelId(channel), reply, "", false)
+            .map(|_| ())
+            .map_err(|e| AdapterError::Send(format!("Failed to send message: {}", e)))
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(discord) = self.discord.take() {
+            discord.logout().unwrap_or_else(|e| println!("Warning: Discord logout failed: {}", e));
+        }
+    }
+
+    fn waker(&self) -> Option<Box<Fn() + Send>> {
+        let events = self.event_sender.clone();
+        Some(Box::new(move || {
+            let _ = events.send(Received::Wake);
+        }))
+    }
 }
@@ -0,0 +1,173 @@
+extern crate regex;
+
+use self::regex::Regex;
+use config::FilterConfig;
+
+use std::collections::BTreeSet;
+
+/// Most characters in a reply when the config doesn't say
+const MAX_LENGTH: usize = 400;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Urls {
+    Allow,
+    Strip,
+    Reject,
+}
+
+/// Checks and cleans up replies before an adapter sends them
+pub struct Filter {
+    words: BTreeSet<String>,
+    patterns: Vec<Regex>,
+    strip_mentions: bool,
+    urls: Urls,
+    max_length: usize,
+    /// Replies starting with any of these aren't sent, so the bot can't be made to run commands
+    blocked_prefixes: Vec<String>,
+}
+
+impl Filter {
+    /// Make the filter for a config that passed `Connection::check`.
+    pub fn new(config: &FilterConfig) -> Filter {
+        Filter{
+            words: config.words.iter().map(|w| w.to_lowercase()).collect(),
+            patterns: config.patterns().unwrap_or_else(|_| Vec::new()),
+            strip_mentions: config.strip_mentions.unwrap_or(true),
+            urls: match config.urls.as_ref().map(|u| u.as_str()) {
+                Some("strip") => Urls::Strip,
+                Some("reject") => Urls::Reject,
+                _ => Urls::Allow,
+            },
+            max_length: config.max_length.unwrap_or(MAX_LENGTH),
+            blocked_prefixes: vec![".".to_string(), "/".to_string()],
+        }
+    }
+
+    /// Clean up a reply, or give back `None` if it can't be sent.
+    pub fn apply(&self, reply: &str) -> Option<String> {
+        let mut words = Vec::new();
+        // Splitting on whitespace also drops line breaks, which could start an IRC command
+        for word in reply.split_whitespace() {
+            let mut word = word.chars().filter(|c| !c.is_control()).collect::<String>();
+            if self.strip_mentions {
+                // Discord pings look like `<@1234>`, `<@!1234>` or `<@&1234>`
+                if word.starts_with("<@") {
+                    continue;
+                }
+                word = word.trim_left_matches('@').to_string();
+            }
+            if is_url(&word) {
+                match self.urls {
+                    Urls::Allow => {},
+                    Urls::Strip => continue,
+                    Urls::Reject => return None,
+                }
+            }
+            if word.is_empty() {
+                continue;
+            }
+            let bare = word.trim_matches(|c: char| !c.is_alphanumeric()).to_lowercase();
+            if self.words.contains(&bare) {
+                return None;
+            }
+            words.push(word);
+        }
+        let reply = words.join(" ");
+        if reply.is_empty()
+            || reply.chars().count() > self.max_length
+            || self.blocked_prefixes.iter().any(|p| reply.starts_with(p.as_str()))
+            || self.patterns.iter().any(|p| p.is_match(&reply))
+        {
+            return None;
+        }
+        Some(reply)
+    }
+}
+
+fn is_url(word: &str) -> bool {
+    word.contains("://") || word.starts_with("www.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filter;
+    use config::FilterConfig;
+
+    fn filter(config: FilterConfig) -> Filter {
+        Filter::new(&config)
+    }
+
+    fn apply(config: FilterConfig, reply: &str) -> Option<String> {
+        filter(config).apply(reply)
+    }
+
+    #[test]
+    fn cleans_up_whitespace() {
+        assert_eq!(apply(FilterConfig::default(), "hello\r\nQUIT  :bye\u{7}"), Some("hello QUIT :bye".to_string()));
+        assert_eq!(apply(FilterConfig::default(), " \n\t"), None);
+    }
+
+    #[test]
+    fn strips_mentions() {
+        assert_eq!(apply(FilterConfig::default(), "hi <@1234> and @everyone"), Some("hi and everyone".to_string()));
+        let keep = FilterConfig{
+            strip_mentions: Some(false),
+            ..FilterConfig::default()
+        };
+        assert_eq!(apply(keep, "hi <@1234> and @everyone"), Some("hi <@1234> and @everyone".to_string()));
+    }
+
+    #[test]
+    fn urls() {
+        let reply = "see http://example.com and www.example.com now";
+        assert_eq!(apply(FilterConfig::default(), reply), Some(reply.to_string()));
+        let strip = FilterConfig{
+            urls: Some("strip".to_string()),
+            ..FilterConfig::default()
+        };
+        assert_eq!(apply(strip, reply), Some("see and now".to_string()));
+        let reject = FilterConfig{
+            urls: Some("reject".to_string()),
+            ..FilterConfig::default()
+        };
+        assert_eq!(apply(reject.clone(), reply), None);
+        assert_eq!(apply(reject, "no links here"), Some("no links here".to_string()));
+    }
+
+    #[test]
+    fn blocked_words_ignore_case_and_punctuation() {
+        let config = FilterConfig{
+            words: vec!["Heck".to_string()],
+            ..FilterConfig::default()
+        };
+        let f = filter(config);
+        assert_eq!(f.apply("oh HECK!"), None);
+        assert_eq!(f.apply("what the heck, then"), None);
+        assert_eq!(f.apply("heckle them"), Some("heckle them".to_string()));
+    }
+
+    #[test]
+    fn blocked_patterns() {
+        let config = FilterConfig{
+            patterns: vec!["(?i)free \\w+".to_string()],
+            ..FilterConfig::default()
+        };
+        let f = filter(config);
+        assert_eq!(f.apply("get FREE stuff"), None);
+        assert_eq!(f.apply("freedom"), Some("freedom".to_string()));
+    }
+
+    #[test]
+    fn length_and_prefixes() {
+        let short = FilterConfig{
+            max_length: Some(5),
+            ..FilterConfig::default()
+        };
+        assert_eq!(apply(short.clone(), "hello"), Some("hello".to_string()));
+        assert_eq!(apply(short, "hello!"), None);
+        assert_eq!(apply(FilterConfig::default(), "/nick evil"), None);
+        assert_eq!(apply(FilterConfig::default(), ".restart"), None);
+        let long = ::std::iter::repeat("a").take(401).collect::<String>();
+        assert_eq!(apply(FilterConfig::default(), &long), None);
+    }
+}
@@ -1,64 +1,131 @@
 extern crate irc;
 use self::irc::client::prelude::*;
-use std::sync::mpsc::channel;
-use std::sync::mpsc::Sender;
-use chat::ChatMessage;
-use chat::ReplyMessage;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use chat::{recv_within, AdapterError, ChatAdapter, ChatMessage, Incoming};
 use std::path::Path;
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
-extern crate itertools;
-use self::itertools::Itertools;
+/// What the adapter waits for
+enum Event {
+    /// Read by the thread of the connection with this number. An old connection's thread can still be reading.
+    Read(usize, Result<Message, String>),
+    /// Stop waiting, so being told to stop is noticed
+    Wake,
+}
 
-const IRC_RECONNECT_WAIT: u64 = 1;
-const IRC_MILI_LIMITER: u64 = 1500;
+pub struct IrcAdapter {
+    config: Config,
+    server: Option<IrcServer>,
+    /// How many times it has connected, which numbers the current connection
+    connections: usize,
+    events: Receiver<Event>,
+    event_sender: Sender<Event>,
+}
 
-pub fn connect<P>(sender: Sender<ReplyMessage>, path: P)
-    where P: AsRef<Path>
-{
-    let config = Config::load(path)
-        .unwrap_or_else(|e| panic!("IRC Fatal: Couldn't load config file: {}", e));
-    let mut last_send = Instant::now();
-    loop {
-        let server = IrcServer::from_config(config.clone())
-            .unwrap_or_else(|e| panic!("IRC Fatal: Couldn't make server: {}", e));
-        server.identify().unwrap_or_else(|e| panic!("IRC Fatal: Failed to identify server: {}", e));
-        for message in server.iter() {
-            let message = message.unwrap_or_else(|e| panic!("IRC Fatal: Failed to get message: {}", e));
-            match message.command {
-                Command::PRIVMSG(target, msg) => {
-                    let name = message.prefix.unwrap_or_else(|| panic!("IRC Fatal: Unable to get msg name"));
-                    let nick = server.config().nickname().to_string();
-                    let chat_message = ChatMessage {
-                        source: target.clone(),
-                        author: name,
-                        message: msg.split(' ').filter(|e| e != &nick).join(" "),
-                    };
-                    if msg.contains(&nick) && Instant::now() - last_send > Duration::from_millis(IRC_MILI_LIMITER) {
-                        let (reply_sender, reply_reciever) = channel();
-                        sender.send(ReplyMessage(chat_message, Some(reply_sender)))
-                            .unwrap_or_else(|e| panic!("IRC Fatal: Message sender closed: {}", e));
-                        let reply_message = reply_reciever.recv()
-                            .unwrap_or_else(|e| panic!("IRC Fatal: Reply receiver failed: {}", e));
-                        if let Some(reply_string) = reply_message {
-                            if let Some(fc) = reply_string.chars().next() {
-                                if fc != '.' && fc != '/' {
-                                    server.send_privmsg(target.as_str(), reply_string.as_str())
-                                        .unwrap_or_else(|e| panic!("IRC Fatal: Failed to send message: {}", e));
-                                }
-                            }
-                        }
-                        last_send = Instant::now();
-                    } else {
-                        sender.send(ReplyMessage(chat_message, None))
-                            .unwrap_or_else(|e| panic!("IRC Fatal: Message sender closed: {}", e));
-                    }
-                },
-                _ => {},
+impl IrcAdapter {
+    pub fn new<P>(path: P) -> Result<IrcAdapter, AdapterError>
+        where P: AsRef<Path>
+    {
+        let config = try!(Config::load(path).map_err(|e| AdapterError::Config(format!("Couldn't load config file: {}", e))));
+        let (event_sender, events) = channel();
+        Ok(IrcAdapter{
+            config: config,
+            server: None,
+            connections: 0,
+            events: events,
+            event_sender: event_sender,
+        })
+    }
+}
+
+impl ChatAdapter for IrcAdapter {
+    fn name(&self) -> &'static str {
+        "IRC"
+    }
+
+    fn nick(&self) -> Option<String> {
+        self.server.as_ref().map(|s| s.config().nickname().to_string())
+    }
+
+    fn connect(&mut self) -> Result<(), AdapterError> {
+        let server = try!(IrcServer::from_config(self.config.clone())
+            .map_err(|e| AdapterError::Connect(format!("Couldn't make server: {}", e))));
+        try!(server.identify().map_err(|e| AdapterError::Connect(format!("Failed to identify server: {}", e))));
+        // Reading blocks, so it is done in its own thread
+        self.connections += 1;
+        let (sender, number) = (self.event_sender.clone(), self.connections);
+        let reader = server.clone();
+        thread::spawn(move || {
+            for message in reader.iter() {
+                if sender.send(Event::Read(number, message.map_err(|e| e.to_string()))).is_err() {
+                    return;
+                }
             }
+            let _ = sender.send(Event::Read(number, Err("Connection ended".to_string())));
+        });
+        self.server = Some(server);
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Option<Duration>) -> Result<Option<Incoming>, AdapterError> {
+        if self.server.is_none() {
+            return Err(AdapterError::Disconnected("Not connected".to_string()));
         }
-        // Connection ended, so we need to wait an amount of time before trying again
-        sleep(Duration::from_secs(IRC_RECONNECT_WAIT));
+        let message = match try!(recv_within(&self.events, timeout)
+            .map_err(|_| AdapterError::Disconnected("Connection ended".to_string())))
+        {
+            Some(Event::Read(number, message)) => {
+                // Left over from an old connection
+                if number != self.connections {
+                    return Ok(None);
+                }
+                try!(message.map_err(AdapterError::Disconnected))
+            },
+            Some(Event::Wake) | None => return Ok(None),
+        };
+        if let Command::PRIVMSG(target, msg) = message.command {
+            let name = match message.prefix {
+                Some(name) => name,
+                None => {
+                    println!("Warning: IRC message without a name ignored");
+                    return Ok(None);
+                },
+            };
+            return Ok(Some(Incoming{
+                message: ChatMessage{
+                    source: target.clone(),
+                    author: name.clone(),
+                    message: msg,
+                },
+                target: target,
+                wants_reply: false,
+                // The prefix is the whole hostmask
+                identity: Some(name),
+            }));
+        }
+        Ok(None)
+    }
+
+    fn send(&mut self, target: &str, reply: Option<&str>) -> Result<(), AdapterError> {
+        match (self.server.as_ref(), reply) {
+            (Some(server), Some(reply)) => {
+                server.send_privmsg(target, reply).map_err(|e| AdapterError::Send(format!("Failed to send message: {}", e)))
+            },
+            _ => Ok(()),
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if let Some(server) = self.server.take() {
+            let _ = server.send_quit("");
+        }
+    }
+
+    fn waker(&self) -> Option<Box<Fn() + Send>> {
+        let events = self.event_sender.clone();
+        Some(Box::new(move || {
+            let _ = events.send(Event::Wake);
+        }))
     }
 }
@@ -1,13 +1,58 @@
 extern crate crossbeam;
+extern crate rand;
+extern crate regex;
 extern crate serde_json;
 
 pub mod irc;
 pub mod discord;
 pub mod server;
+pub mod websocket;
+pub mod registry;
+pub mod owner;
+pub mod filter;
 
-use std::sync::mpsc::Sender;
+pub use self::registry::Registry;
+use self::filter::Filter;
+use self::registry::{set_error, set_status, SharedStatus, Status};
+use cli::Commands;
+use config::{self, Connection, ReplyPolicy};
+use self::regex::Regex;
 
-pub struct ReplyMessage(pub ChatMessage, pub Option<Sender<Option<String>>>);
+use rand::Rng;
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvError, RecvTimeoutError, Sender};
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How long to wait before connecting again the first time a connection fails
+const RECONNECT_WAIT_MILLIS: u64 = 1000;
+/// Longest wait between attempts to connect, however many have failed
+const RECONNECT_WAIT_MAX_MILLIS: u64 = 300000;
+/// How often a wait to reconnect checks whether the adapter was stopped
+const STOP_CHECK_MILLIS: u64 = 250;
+/// Least time between replies to mentions in a channel without a cooldown
+const MENTION_LIMIT_MILLIS: u64 = 1500;
+/// How many times to ask for something to say before giving up on everything failing the filter
+const FILTER_ATTEMPTS: usize = 5;
+
+/// Set to make an adapter stop the next time it hears anything
+pub type Stop = Arc<AtomicBool>;
+
+pub enum ReplyMessage {
+    /// Something heard, with where to send a reply if one is wanted
+    Heard(ChatMessage, Option<Sender<Option<String>>>),
+    /// Ask for something to say in a source that has gone quiet
+    Initiate(String, Sender<Option<String>>),
+    /// Something said in a source didn't pass the filter, so it was never sent. Something else to say is asked
+    /// for if there is somewhere to send it.
+    Rejected(String, String, Option<Sender<Option<String>>>),
+    /// Something said in a source was sent, so it can be forgotten later
+    Sent(String, String),
+}
 
 #[derive(Deserialize, Debug)]
 pub struct ChatMessage {
@@ -15,3 +60,580 @@ pub struct ChatMessage {
     pub author: String,
     pub message: String,
 }
+
+/// Why an adapter failed
+#[derive(Debug)]
+pub enum AdapterError {
+    /// The adapter's config can't be used, so trying again won't help
+    Config(String),
+    /// Couldn't connect or log in
+    Connect(String),
+    /// Lost the connection
+    Disconnected(String),
+    /// Couldn't send a reply
+    Send(String),
+}
+
+impl AdapterError {
+    /// Whether connecting again can't help
+    pub fn is_fatal(&self) -> bool {
+        match *self {
+            AdapterError::Config(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for AdapterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AdapterError::Config(ref e) => write!(f, "bad config: {}", e),
+            AdapterError::Connect(ref e) => write!(f, "unable to connect: {}", e),
+            AdapterError::Disconnected(ref e) => write!(f, "disconnected: {}", e),
+            AdapterError::Send(ref e) => write!(f, "unable to send: {}", e),
+        }
+    }
+}
+
+impl From<AdapterError> for String {
+    fn from(e: AdapterError) -> String {
+        e.to_string()
+    }
+}
+
+/// A message an adapter heard
+pub struct Incoming {
+    pub message: ChatMessage,
+    /// Where a reply should go, in whatever form the adapter understands
+    pub target: String,
+    /// Asked for a reply directly instead of by mentioning the bot
+    pub wants_reply: bool,
+    /// Who sent it in a way that can't be faked, like an IRC hostmask, if the backend can tell
+    pub identity: Option<String>,
+}
+
+/// The transport of a chat backend. Everything else, like deciding when to reply, is done by `run`.
+pub trait ChatAdapter {
+    /// Name of the backend for logs
+    fn name(&self) -> &'static str;
+
+    /// Name the bot goes by, so that mentioning it asks for a reply
+    fn nick(&self) -> Option<String> {
+        None
+    }
+
+    /// Connect, or connect again after `receive` failed.
+    fn connect(&mut self) -> Result<(), AdapterError>;
+
+    /// Wait for the next message, giving up with `None` once the timeout passes. `None` can also be given early
+    /// when something arrived that isn't worth passing on. An error means the connection was lost.
+    fn receive(&mut self, timeout: Option<Duration>) -> Result<Option<Incoming>, AdapterError>;
+
+    /// Send what the bot replied with to a target from `receive`. The reply is `None` when the bot had nothing to
+    /// say, which only matters to adapters that promised an answer.
+    fn send(&mut self, target: &str, reply: Option<&str>) -> Result<(), AdapterError>;
+
+    /// Disconnect for good.
+    fn shutdown(&mut self) {}
+
+    /// Get something that makes a blocked `receive` return, so a stop is noticed without waiting for a message.
+    fn waker(&self) -> Option<Box<Fn() + Send>> {
+        None
+    }
+
+    /// Whether a target from `receive` can be sent to more than once, so the bot can say things by itself
+    fn initiates(&self) -> bool {
+        true
+    }
+}
+
+/// Wait on a channel until the timeout passes, if there is one. `None` means it passed.
+pub fn recv_within<T>(receiver: &Receiver<T>, timeout: Option<Duration>) -> Result<Option<T>, RecvError> {
+    match timeout {
+        Some(timeout) => match receiver.recv_timeout(timeout) {
+            Ok(t) => Ok(Some(t)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => Err(RecvError),
+        },
+        None => receiver.recv().map(Some),
+    }
+}
+
+/// What the middleware remembers about a channel
+struct Channel {
+    policy: ReplyPolicy,
+    /// Where the last message came from, which is where anything said by itself goes
+    target: String,
+    last_heard: Instant,
+    last_reply: Option<Instant>,
+    /// Said something by itself since anyone last spoke
+    initiated: bool,
+}
+
+/// Handling shared by every adapter: ignoring messages, mention detection, each channel's reply policy and the
+/// filter replies pass through.
+pub struct Middleware {
+    connection: Connection,
+    ignored_authors: Vec<Regex>,
+    owners: Vec<Regex>,
+    /// Channels an owner told to be quiet
+    muted: BTreeSet<String>,
+    filter: Filter,
+    channels: BTreeMap<String, Channel>,
+}
+
+impl Middleware {
+    /// Make the middleware for a connection that passed `Connection::check`.
+    pub fn new(connection: Connection) -> Middleware {
+        Middleware{
+            ignored_authors: connection.ignore.authors().unwrap_or_else(|_| Vec::new()),
+            owners: config::patterns(&connection.owners).unwrap_or_else(|_| Vec::new()),
+            muted: BTreeSet::new(),
+            filter: Filter::new(&connection.filter),
+            connection: connection,
+            channels: BTreeMap::new(),
+        }
+    }
+
+    /// Whether a message should be dropped without being learned from or replied to
+    pub fn ignores(&self, message: &ChatMessage) -> bool {
+        self.connection.policy(&message.source).ignore.unwrap_or(false)
+            || self.connection.ignore.prefixes.iter().any(|p| message.message.trim_left().starts_with(p.as_str()))
+            || self.ignored_authors.iter().any(|a| a.is_match(&message.author))
+    }
+
+    /// Whether someone can give owner commands
+    pub fn is_owner(&self, identity: Option<&String>) -> bool {
+        identity.map_or(false, |i| self.owners.iter().any(|o| o.is_match(i)))
+    }
+
+    /// Stop or start replying and saying anything by itself in a channel.
+    pub fn mute(&mut self, source: &str, muted: bool) {
+        if muted {
+            self.muted.insert(source.to_string());
+        } else {
+            self.muted.remove(source);
+        }
+    }
+
+    /// Whether what is said in a channel is learned from
+    pub fn learns(&self, source: &str) -> bool {
+        self.connection.policy(source).learn.unwrap_or(true)
+    }
+
+    /// Take the nick out of a message, saying whether it was there.
+    pub fn mention(&self, nick: &str, message: &mut String) -> bool {
+        if !message.contains(nick) {
+            return false;
+        }
+        *message = message.split(' ').filter(|w| *w != nick).collect::<Vec<_>>().join(" ");
+        true
+    }
+
+    /// Note a message heard in a channel, saying whether its policy allows a reply and counting the reply if so.
+    pub fn hear(&mut self, source: &str, target: &str, wants_reply: bool, mentioned: bool) -> bool {
+        let now = Instant::now();
+        let connection = &self.connection;
+        let channel = self.channels.entry(source.to_string()).or_insert_with(|| Channel{
+            policy: connection.policy(source),
+            target: String::new(),
+            last_heard: now,
+            last_reply: None,
+            initiated: false,
+        });
+        channel.target = target.to_string();
+        channel.last_heard = now;
+        channel.initiated = false;
+
+        // Anything else is replied to by chance, which is certain when replying to anything and none when only
+        // replying to mentions, unless a probability is given
+        let chance = match channel.policy.reply.as_ref().map(|r| r.as_str()) {
+            Some("never") => None,
+            Some("always") => Some(channel.policy.probability.unwrap_or(1.0)),
+            _ => Some(channel.policy.probability.unwrap_or(0.0)),
+        };
+        let wanted = match chance {
+            Some(p) => wants_reply || mentioned || rand::thread_rng().gen::<f64>() < p,
+            None => false,
+        };
+        let limit = match channel.policy.cooldown {
+            Some(cooldown) => Some(Duration::from_millis((cooldown * 1000.0) as u64)),
+            // Mentions are limited anyway, so people can't make the bot flood a channel
+            None if mentioned && !wants_reply => Some(Duration::from_millis(MENTION_LIMIT_MILLIS)),
+            None => None,
+        };
+        let cooled = match (channel.last_reply, limit) {
+            (Some(last), Some(limit)) => now - last >= limit,
+            _ => true,
+        };
+        if wanted && cooled && !quiet(&channel.policy) && !self.muted.contains(source) {
+            channel.last_reply = Some(now);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// How long until a channel has been quiet for long enough to say something in, if any is waiting to be
+    pub fn lull(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.channels.values()
+            .filter(|c| !c.initiated)
+            .filter_map(|c| c.policy.initiate_after.map(|after| c.last_heard + Duration::from_secs(after)))
+            .min()
+            .map(|at| if at > now { at - now } else { Duration::from_millis(0) })
+    }
+
+    /// Get every channel that has been quiet for long enough to say something in, as its source and target. Each
+    /// lull only counts once.
+    pub fn lulled(&mut self) -> Vec<(String, String)> {
+        let now = Instant::now();
+        let mut lulled = Vec::new();
+        for (source, channel) in &mut self.channels {
+            let due = match channel.policy.initiate_after {
+                Some(after) => !channel.initiated && now - channel.last_heard >= Duration::from_secs(after),
+                None => false,
+            };
+            if !due {
+                continue;
+            }
+            channel.initiated = true;
+            if channel.policy.reply.as_ref().map_or(true, |r| r.as_str() != "never") && !quiet(&channel.policy)
+                && !self.muted.contains(source)
+            {
+                channel.last_reply = Some(now);
+                lulled.push((source.clone(), channel.target.clone()));
+            }
+        }
+        lulled
+    }
+}
+
+/// Whether it is currently a policy's quiet hours
+fn quiet(policy: &ReplyPolicy) -> bool {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    quiet_at(policy, (now / 3600 % 24) as u32)
+}
+
+/// Whether an hour of the day, in UTC, is in a policy's quiet hours
+fn quiet_at(policy: &ReplyPolicy, hour: u32) -> bool {
+    let (from, until) = match policy.quiet_hours {
+        Some(hours) => hours,
+        None => return false,
+    };
+    if from <= until {
+        hour >= from && hour < until
+    } else {
+        // Goes past midnight
+        hour >= from || hour < until
+    }
+}
+
+/// Waits between attempts to connect, doubling after every failure up to a limit. Each wait is jittered so
+/// adapters that lost their connections together don't all come back at once.
+struct Backoff {
+    next: u64,
+}
+
+impl Backoff {
+    fn new() -> Backoff {
+        Backoff{next: RECONNECT_WAIT_MILLIS}
+    }
+
+    fn reset(&mut self) {
+        self.next = RECONNECT_WAIT_MILLIS;
+    }
+
+    /// Get how many milliseconds to wait before the next attempt, somewhere between half and all of the full wait.
+    fn next_wait(&mut self) -> u64 {
+        let millis = rand::thread_rng().gen_range(self.next / 2, self.next + 1);
+        self.next = cmp::min(self.next * 2, RECONNECT_WAIT_MAX_MILLIS);
+        millis
+    }
+
+    /// Wait before the next attempt, returning early if the adapter was stopped.
+    fn wait(&mut self, stop: &Stop) {
+        let millis = self.next_wait();
+        let until = Instant::now() + Duration::from_millis(millis);
+        while !stop.load(Ordering::SeqCst) {
+            let now = Instant::now();
+            if now >= until {
+                break;
+            }
+            sleep(cmp::min(until - now, Duration::from_millis(STOP_CHECK_MILLIS)));
+        }
+    }
+}
+
+/// Ask the main loop for something to say in a source, asking again for as long as what it says doesn't pass the
+/// filter. Gives what the lexicon said along with what passed the filter, which is what gets sent. An error means
+/// the main loop is gone.
+fn ask<F>(sender: &Sender<ReplyMessage>, middleware: &Middleware, source: &str, first: F)
+    -> Result<Option<(String, String)>, ()>
+    where F: FnOnce(Sender<Option<String>>) -> ReplyMessage
+{
+    let (reply_sender, mut reply_receiver) = channel();
+    try!(sender.send(first(reply_sender)).map_err(|_| ()));
+    for attempt in 1.. {
+        let said = match try!(reply_receiver.recv().map_err(|_| ())) {
+            Some(said) => said,
+            None => return Ok(None),
+        };
+        if let Some(filtered) = middleware.filter.apply(&said) {
+            return Ok(Some((said, filtered)));
+        }
+        // Whatever failed was never said, so it has to be taken back
+        if attempt == FILTER_ATTEMPTS {
+            try!(sender.send(ReplyMessage::Rejected(source.to_string(), said, None)).map_err(|_| ()));
+            break;
+        }
+        let (next_sender, next_receiver) = channel();
+        try!(sender.send(ReplyMessage::Rejected(source.to_string(), said, Some(next_sender))).map_err(|_| ()));
+        reply_receiver = next_receiver;
+    }
+    Ok(None)
+}
+
+/// Run an adapter until it is stopped, passing everything it hears to the main loop and sending back replies.
+/// Errors are logged and kept in the status rather than ending the adapter, unless trying again can't help.
+pub fn run<A: ChatAdapter>(mut adapter: A, mut middleware: Middleware, sender: Sender<ReplyMessage>,
+    commands: &Commands, stop: Stop, status: &SharedStatus)
+{
+    let name = adapter.name();
+    let mut backoff = Backoff::new();
+    'connection: while !stop.load(Ordering::SeqCst) {
+        if let Err(e) = adapter.connect() {
+            println!("Warning: {} failed to connect: {}", name, e);
+            set_error(status, &e);
+            if e.is_fatal() {
+                break;
+            }
+            set_status(status, Status::Reconnecting);
+            backoff.wait(&stop);
+            continue;
+        }
+        set_status(status, Status::Connected);
+        loop {
+            let initiates = adapter.initiates();
+            let incoming = adapter.receive(if initiates { middleware.lull() } else { None });
+            if stop.load(Ordering::SeqCst) {
+                break 'connection;
+            }
+            let incoming = match incoming {
+                Ok(incoming) => incoming,
+                Err(e) => {
+                    println!("Warning: {} lost its connection: {}", name, e);
+                    set_error(status, &e);
+                    if e.is_fatal() {
+                        break 'connection;
+                    }
+                    break;
+                },
+            };
+            if initiates {
+                for (source, target) in middleware.lulled() {
+                    let said = match ask(&sender, &middleware, &source, |s| ReplyMessage::Initiate(source.clone(), s)) {
+                        Ok(said) => said,
+                        Err(_) => break 'connection,
+                    };
+                    if let Some((said, filtered)) = said {
+                        match adapter.send(&target, Some(&filtered)) {
+                            Ok(_) => {
+                                if sender.send(ReplyMessage::Sent(source, said)).is_err() {
+                                    break 'connection;
+                                }
+                            },
+                            Err(e) => {
+                                println!("Warning: {} failed to send a message: {}", name, e);
+                                set_error(status, &e);
+                            },
+                        }
+                    }
+                }
+            }
+            let Incoming{mut message, target, wants_reply, identity} = match incoming {
+                Some(incoming) => incoming,
+                None => continue,
+            };
+            // Hearing something means the connection works, so the next loss starts waiting from the beginning
+            backoff.reset();
+            if let Some(command) = owner::parse(&message.message) {
+                if !middleware.is_owner(identity.as_ref()) {
+                    println!("Warning: Ignored {} owner command from {}", name, message.author);
+                    // Whoever asked directly is still waiting for an answer
+                    if wants_reply {
+                        if let Err(e) = adapter.send(&target, None) {
+                            println!("Warning: {} failed to send a reply: {}", name, e);
+                            set_error(status, &e);
+                        }
+                    }
+                    continue;
+                }
+                let (said, disconnect) = owner::run(command, &message.source, &mut middleware, commands);
+                if let Err(e) = adapter.send(&target, Some(&said)) {
+                    println!("Warning: {} failed to send a reply: {}", name, e);
+                    set_error(status, &e);
+                }
+                if disconnect {
+                    break 'connection;
+                }
+                continue;
+            }
+            let source = message.source.clone();
+            let reply = if middleware.ignores(&message) {
+                None
+            } else {
+                let mentioned = match adapter.nick() {
+                    Some(nick) => middleware.mention(&nick, &mut message.message),
+                    None => false,
+                };
+                let learns = middleware.learns(&message.source);
+                if middleware.hear(&message.source, &target, wants_reply, mentioned) {
+                    // Replying without learning what was said is the same as saying something by itself
+                    let reply = ask(&sender, &middleware, &source, |s| if learns {
+                        ReplyMessage::Heard(message, Some(s))
+                    } else {
+                        ReplyMessage::Initiate(message.source, s)
+                    });
+                    match reply {
+                        Ok(reply) => reply,
+                        Err(_) => break 'connection,
+                    }
+                } else {
+                    if learns && sender.send(ReplyMessage::Heard(message, None)).is_err() {
+                        break 'connection;
+                    }
+                    None
+                }
+            };
+            // Anything asked directly still gets an answer, even if it is nothing
+            if reply.is_none() && !wants_reply {
+                continue;
+            }
+            match adapter.send(&target, reply.as_ref().map(|r| r.1.as_str())) {
+                Ok(_) => {
+                    if let Some((said, _)) = reply {
+                        if sender.send(ReplyMessage::Sent(source, said)).is_err() {
+                            break 'connection;
+                        }
+                    }
+                },
+                Err(e) => {
+                    println!("Warning: {} failed to send a reply: {}", name, e);
+                    set_error(status, &e);
+                },
+            }
+        }
+        // Connection ended, so we need to wait an amount of time before trying again
+        set_status(status, Status::Reconnecting);
+        backoff.wait(&stop);
+    }
+    adapter.shutdown();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{quiet_at, Backoff, Middleware, RECONNECT_WAIT_MAX_MILLIS, RECONNECT_WAIT_MILLIS};
+    use config::{Connection, ReplyPolicy};
+
+    fn hours(from: u32, until: u32) -> ReplyPolicy {
+        ReplyPolicy{
+            quiet_hours: Some((from, until)),
+            ..ReplyPolicy::default()
+        }
+    }
+
+    fn middleware(policy: ReplyPolicy) -> Middleware {
+        let mut connection = Connection::new("irc", None, None);
+        connection.channels.insert("#chat".to_string(), policy);
+        Middleware::new(connection)
+    }
+
+    #[test]
+    fn quiet_hours() {
+        assert!(!quiet_at(&ReplyPolicy::default(), 3));
+        let day = hours(9, 17);
+        assert!(quiet_at(&day, 9));
+        assert!(quiet_at(&day, 16));
+        assert!(!quiet_at(&day, 17));
+        assert!(!quiet_at(&day, 8));
+    }
+
+    #[test]
+    fn quiet_hours_past_midnight() {
+        let night = hours(23, 7);
+        assert!(quiet_at(&night, 23));
+        assert!(quiet_at(&night, 0));
+        assert!(quiet_at(&night, 6));
+        assert!(!quiet_at(&night, 7));
+        assert!(!quiet_at(&night, 22));
+        assert!(!quiet_at(&night, 12));
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let mut backoff = Backoff::new();
+        let mut full = RECONNECT_WAIT_MILLIS;
+        for _ in 0..20 {
+            let wait = backoff.next_wait();
+            assert!(wait >= full / 2 && wait <= full);
+            full = ::std::cmp::min(full * 2, RECONNECT_WAIT_MAX_MILLIS);
+        }
+        assert_eq!(backoff.next, RECONNECT_WAIT_MAX_MILLIS);
+        backoff.reset();
+        assert!(backoff.next_wait() <= RECONNECT_WAIT_MILLIS);
+    }
+
+    #[test]
+    fn reply_modes() {
+        let mut never = middleware(ReplyPolicy{reply: Some("never".to_string()), ..ReplyPolicy::default()});
+        assert!(!never.hear("#chat", "#chat", true, true));
+        let mut mention = middleware(ReplyPolicy::default());
+        assert!(!mention.hear("#chat", "#chat", false, false));
+        assert!(mention.hear("#chat", "#chat", true, false));
+        let mut always = middleware(ReplyPolicy{
+            reply: Some("always".to_string()),
+            probability: Some(1.0),
+            ..ReplyPolicy::default()
+        });
+        assert!(always.hear("#chat", "#chat", false, false));
+    }
+
+    #[test]
+    fn mentions_and_probability() {
+        let mut sometimes = middleware(ReplyPolicy{probability: Some(1.0), ..ReplyPolicy::default()});
+        assert!(sometimes.hear("#chat", "#chat", false, false));
+        let mut rarely = middleware(ReplyPolicy{probability: Some(0.0), ..ReplyPolicy::default()});
+        assert!(!rarely.hear("#chat", "#chat", false, false));
+        assert!(rarely.hear("#chat", "#chat", false, true));
+    }
+
+    #[test]
+    fn mentions_are_limited() {
+        let mut m = middleware(ReplyPolicy::default());
+        assert!(m.hear("#chat", "#chat", false, true));
+        assert!(!m.hear("#chat", "#chat", false, true));
+        // Being asked directly isn't limited without a cooldown
+        assert!(m.hear("#chat", "#chat", true, false));
+    }
+
+    #[test]
+    fn muted_channels() {
+        let mut m = middleware(ReplyPolicy::default());
+        m.mute("#chat", true);
+        assert!(!m.hear("#chat", "#chat", true, false));
+        m.mute("#chat", false);
+        assert!(m.hear("#chat", "#chat", true, false));
+    }
+
+    #[test]
+    fn initiates_once_per_lull() {
+        let mut m = middleware(ReplyPolicy{initiate_after: Some(0), ..ReplyPolicy::default()});
+        assert!(m.lull().is_none());
+        m.hear("#chat", "target", false, false);
+        assert!(m.lull().is_some());
+        assert_eq!(m.lulled(), vec![("#chat".to_string(), "target".to_string())]);
+        assert!(m.lull().is_none());
+        assert!(m.lulled().is_empty());
+    }
+}
@@ -0,0 +1,64 @@
+use chat::Middleware;
+use cli::{Commands, Decision, Reply, STATUS_OK};
+
+/// What owner commands start with, like `!seifmios mute`
+pub const PREFIX: &'static str = "!seifmios";
+
+/// Split an owner command into its words, if a message is one.
+pub fn parse(message: &str) -> Option<Vec<String>> {
+    let mut words = message.split_whitespace();
+    if words.next() != Some(PREFIX) {
+        return None;
+    }
+    Some(words.map(|w| w.to_string()).collect())
+}
+
+/// Carry out a command an owner gave in a source, giving back what to say about it and whether the adapter should
+/// stop. Anything the cli also does is passed on to it, so it is decided the same way.
+pub fn run(command: Vec<String>, source: &str, middleware: &mut Middleware, commands: &Commands) -> (String, bool) {
+    match command.get(0).map(|c| c.as_str()) {
+        Some("mute") if command.len() == 1 => {
+            middleware.mute(source, true);
+            ("Muted".to_string(), false)
+        },
+        Some("unmute") if command.len() == 1 => {
+            middleware.mute(source, false);
+            ("Unmuted".to_string(), false)
+        },
+        Some("disconnect") if command.len() == 1 => ("Disconnecting".to_string(), true),
+        Some("stats") | Some("get") | Some("set") => (said(commands.run_command(command)), false),
+        Some("forget") if command.get(1).map_or(false, |c| c == "last") && command.len() <= 3 => {
+            let remove = match command.get(2).map(|c| c.as_str()) {
+                Some("remove") => true,
+                Some(_) => return (usage(), false),
+                None => false,
+            };
+            // Whatever was said here is what gets forgotten
+            (said(commands.run(Decision::ForgetLast(Some(source.to_string()), remove))), false)
+        },
+        _ => (usage(), false),
+    }
+}
+
+fn usage() -> String {
+    format!("Usage: {} <mute|unmute|disconnect|forget last [remove]|stats|get|set>", PREFIX)
+}
+
+/// Put every line of the replies to a cli command together so they can be said in one message.
+fn said(replies: Result<Vec<Reply>, String>) -> String {
+    match replies {
+        Ok(replies) => {
+            let lines = replies.iter()
+                .filter_map(|r| r.payload.as_ref().map(|p| (r.status == STATUS_OK, p)))
+                .flat_map(|(ok, p)| p.lines().map(move |l| if ok { l.to_string() } else { format!("Error: {}", l) }))
+                .filter(|l| !l.is_empty())
+                .collect::<Vec<_>>();
+            if lines.is_empty() {
+                "Done".to_string()
+            } else {
+                lines.join("; ")
+            }
+        },
+        Err(e) => format!("Error: {}", e),
+    }
+}
@@ -0,0 +1,248 @@
+use chat::{run, ChatAdapter, Middleware, ReplyMessage, Stop};
+use cli::{Commands, SocketLend};
+use config::Connection;
+
+use std::any::Any;
+use std::fmt::Display;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::thread::{spawn, JoinHandle};
+use std::time::Instant;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Status {
+    Connecting,
+    Connected,
+    /// Lost its connection and is waiting to try again
+    Reconnecting,
+    Stopped,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Status::Connecting => "connecting",
+            Status::Connected => "connected",
+            Status::Reconnecting => "reconnecting",
+            Status::Stopped => "stopped",
+        }
+    }
+}
+
+/// Status kept up to date by an adapter's thread, along with the last thing that went wrong
+pub type SharedStatus = Arc<Mutex<(Status, Option<String>)>>;
+
+pub fn set_status(status: &SharedStatus, new: Status) {
+    if let Ok(mut s) = status.lock() {
+        s.0 = new;
+    }
+}
+
+pub fn set_error<E: Display>(status: &SharedStatus, error: &E) {
+    if let Ok(mut s) = status.lock() {
+        s.1 = Some(error.to_string());
+    }
+}
+
+/// Something running in its own thread that can be told to stop
+struct Running {
+    id: usize,
+    connection: Connection,
+    /// Started from the config rather than the cli, so reloading can stop it
+    configured: bool,
+    started: Instant,
+    status: SharedStatus,
+    stop: Stop,
+    waker: Option<Box<Fn() + Send>>,
+    thread: JoinHandle<()>,
+}
+
+/// Every adapter that is running, by ID
+pub struct Registry {
+    sender: Sender<ReplyMessage>,
+    /// For owner commands given in chat
+    commands: Commands,
+    running: Vec<Running>,
+    /// Told to stop and woken, but may still be waiting on the main loop before it can notice
+    stopping: Vec<Running>,
+    /// Connections to start once everything stopping has finished, so they can bind the same addresses
+    waiting: Vec<(Connection, bool)>,
+    next_id: usize,
+}
+
+impl Registry {
+    pub fn new(sender: Sender<ReplyMessage>, commands: Commands) -> Registry {
+        Registry{
+            sender: sender,
+            commands: commands,
+            running: Vec::new(),
+            stopping: Vec::new(),
+            waiting: Vec::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Start a chat adapter in its own thread, returning its ID.
+    pub fn start<A>(&mut self, connection: Connection, configured: bool, adapter: A) -> usize
+        where A: ChatAdapter + Send + 'static
+    {
+        let waker = adapter.waker();
+        let (sender, commands) = (self.sender.clone(), self.commands.clone());
+        let middleware = Middleware::new(connection.clone());
+        self.launch(connection, configured, waker, move |stop, status| {
+            run(adapter, middleware, sender, &commands, stop, &status)
+        })
+    }
+
+    /// Start anything else that should be stopped along with the adapters, like the HTTP API. It counts as
+    /// connected for as long as it runs.
+    pub fn start_with<F>(&mut self, connection: Connection, configured: bool, waker: Option<Box<Fn() + Send>>, f: F)
+        -> usize
+        where F: FnOnce(Stop) + Send + 'static
+    {
+        self.launch(connection, configured, waker, move |stop, status| {
+            set_status(&status, Status::Connected);
+            f(stop);
+        })
+    }
+
+    fn launch<F>(&mut self, connection: Connection, configured: bool, waker: Option<Box<Fn() + Send>>, f: F) -> usize
+        where F: FnOnce(Stop, SharedStatus) + Send + 'static
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let status = Arc::new(Mutex::new((Status::Connecting, None)));
+        let (thread_stop, thread_status) = (stop.clone(), status.clone());
+        let kind = connection.kind.clone();
+        let thread = spawn(move || {
+            // A panic would otherwise leave it looking connected
+            let result = catch_unwind(AssertUnwindSafe(|| f(thread_stop, thread_status.clone())));
+            if let Err(panic) = result {
+                let message = panic_message(&panic);
+                println!("Warning: {} connection died: {}", kind, message);
+                set_error(&thread_status, &message);
+            }
+            set_status(&thread_status, Status::Stopped);
+        });
+        let id = self.next_id;
+        self.next_id += 1;
+        self.running.push(Running{
+            id: id,
+            connection: connection,
+            configured: configured,
+            started: Instant::now(),
+            status: status,
+            stop: stop,
+            waker: waker,
+            thread: thread,
+        });
+        id
+    }
+
+    /// Show every connection with its status and uptime.
+    pub fn list(&self, socket: &mut SocketLend) {
+        if self.running.is_empty() {
+            socket.msg("No connections");
+        }
+        for running in &self.running {
+            let c = &running.connection;
+            let (status, error) = running.status.lock().map(|s| s.clone()).unwrap_or((Status::Stopped, None));
+            let target = c.config.as_ref().or(c.address.as_ref()).map(|t| format!(" `{}`", t))
+                .unwrap_or_else(String::new);
+            socket.msg(&format!("{}: {}{} - {}, up {}{}{}", running.id, c.kind, target, status.name(),
+                uptime(running.started.elapsed().as_secs()), if running.configured { " (configured)" } else { "" },
+                error.map(|e| format!(", last error: {}", e)).unwrap_or_else(String::new)));
+        }
+    }
+
+    /// Stop a connection, giving back what it was started from and whether it came from the config.
+    pub fn stop(&mut self, id: usize) -> Option<(Connection, bool)> {
+        let index = match self.running.iter().position(|r| r.id == id) {
+            Some(index) => index,
+            None => return None,
+        };
+        let running = self.running.remove(index);
+        let result = (running.connection.clone(), running.configured);
+        self.stop_running(running);
+        Some(result)
+    }
+
+    /// Start a connection once everything that is stopping has finished. `reap` gives it back when it is time.
+    pub fn start_later(&mut self, connection: Connection, configured: bool) {
+        self.waiting.push((connection, configured));
+    }
+
+    /// Whether anything told to stop is still running
+    pub fn is_stopping(&self) -> bool {
+        !self.stopping.is_empty()
+    }
+
+    /// Let go of whatever has finished stopping. Once nothing is left stopping, gives the connections waiting to
+    /// be started.
+    pub fn reap(&mut self) -> Vec<(Connection, bool)> {
+        let (finished, stopping): (Vec<_>, Vec<_>) = self.stopping.drain(..).partition(|r| {
+            r.status.lock().map(|s| s.0 == Status::Stopped).unwrap_or(true)
+        });
+        self.stopping = stopping;
+        for running in finished {
+            // It has already stopped, so this doesn't block
+            let _ = running.thread.join();
+        }
+        if self.stopping.is_empty() {
+            self.waiting.drain(..).collect()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Whether a connection was started from the config, or is waiting to be
+    pub fn is_configured(&self, connection: &Connection) -> bool {
+        self.running.iter().any(|r| r.configured && r.connection == *connection) ||
+            self.waiting.iter().any(|&(ref c, configured)| configured && c == connection)
+    }
+
+    /// Stop every configured connection that isn't in `connections`, returning how many were stopped.
+    pub fn stop_unconfigured(&mut self, connections: &[Connection]) -> usize {
+        let (kept, removed): (Vec<_>, Vec<_>) = self.running.drain(..)
+            .partition(|r| !r.configured || connections.contains(&r.connection));
+        self.running = kept;
+        self.waiting.retain(|&(ref c, configured)| !configured || connections.contains(c));
+        let stopped = removed.len();
+        for running in removed {
+            self.stop_running(running);
+        }
+        stopped
+    }
+
+    /// Tell something to stop. Nothing is waited for here, since the thread may be waiting on the main loop
+    /// itself. Anything that can be woken is kept until `reap` sees it finish, so whatever it had bound is known to
+    /// be free again; anything else finishes in its own time after it next hears anything.
+    fn stop_running(&mut self, running: Running) {
+        running.stop.store(true, Ordering::SeqCst);
+        if let Some(ref wake) = running.waker {
+            wake();
+        }
+        if running.waker.is_some() {
+            self.stopping.push(running);
+        }
+    }
+}
+
+fn panic_message(panic: &Box<Any + Send>) -> String {
+    if let Some(s) = panic.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "panicked".to_string()
+    }
+}
+
+fn uptime(seconds: u64) -> String {
+    match seconds {
+        0...59 => format!("{}s", seconds),
+        60...3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
@@ -1,34 +1,271 @@
 extern crate serde_json;
-use std::net::TcpListener;
-use std::io::{BufReader, BufRead};
-use std::thread;
-use chat::ChatMessage;
-use chat::ReplyMessage;
-use std::sync::mpsc::Sender;
-
-pub fn listen(sender: Sender<ReplyMessage>) {
-    let listener = TcpListener::bind("127.0.0.1:2933").expect("Failed to bind port");
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                let sender = sender.clone();
-                thread::spawn(move || {
-                    let reader = BufReader::new(stream);
-                    for line in reader.lines() {
-                        let message: Result<ChatMessage, _> = serde_json::from_str(line.unwrap().as_str());
-                        match message {
-                            Ok(message) => {
-                                println!("{:?}", message);
-                                let _ = sender.send(ReplyMessage(message, None));
-                            },
-                            Err(e) => println!("Error parsing json: {:?}", e),
-                        }
-                    }
-                });
+use self::serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs;
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::io::{self, BufReader, BufRead, Read, Write};
+use std::path::PathBuf;
+use std::thread::{self, JoinHandle};
+use chat::{recv_within, AdapterError, ChatAdapter, ChatMessage, Incoming};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::time::Duration;
+
+/// Address listened on when none is given
+pub const DEFAULT_ADDRESS: &'static str = "127.0.0.1:2933";
+/// Addresses starting with this are paths of Unix domain sockets
+const UNIX_PREFIX: &'static str = "unix:";
+
+/// A line sent to the server. `{"source": "s", "author": "a", "message": "m"}` is just told to the bot.
+#[derive(Deserialize, Debug)]
+struct Request {
+    /// `tell`, the default, or `respond` to get a reply without telling anything
+    #[serde(rename="type")]
+    kind: Option<String>,
+    source: String,
+    #[serde(default)]
+    author: String,
+    #[serde(default)]
+    message: String,
+    /// Write back a line with the reply
+    reply: Option<bool>,
+    /// Echoed back with the reply, which also implies wanting one
+    id: Option<Value>,
+}
+
+/// A line written back for requests that wanted a reply, or that couldn't be read
+#[derive(Serialize, Debug)]
+struct Response {
+    id: Option<Value>,
+    /// What the bot said, which is null if it had nothing to say
+    reply: Option<String>,
+    error: Option<String>,
+}
+
+/// A bound listener that hasn't started accepting yet
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+/// Where a response goes, shared by every request from a client
+type Writer = Arc<Mutex<Box<Write + Send>>>;
+
+/// A request waiting for its response
+struct Pending {
+    writer: Writer,
+    id: Option<Value>,
+}
+
+enum Event {
+    Request(Incoming, Option<Pending>),
+    /// Makes `receive` return so a stop is noticed
+    Wake,
+}
+
+pub struct ServerAdapter {
+    address: String,
+    // Taken when accepting starts
+    listener: Option<Listener>,
+    accepting: Option<JoinHandle<()>>,
+    events: Receiver<Event>,
+    event_sender: Sender<Event>,
+    pending: BTreeMap<u64, Pending>,
+    next_request: u64,
+    closed: Arc<AtomicBool>,
+}
+
+impl ServerAdapter {
+    /// Bind an address like `127.0.0.1:2933`, `[::1]:2933` or `unix:/run/seifmios.sock`. This is done before
+    /// starting the adapter so a failure can be reported.
+    pub fn bind(address: &str) -> Result<ServerAdapter, AdapterError> {
+        let bind_error = |e: io::Error| AdapterError::Connect(format!("Unable to bind `{}`: {}", address, e));
+        let listener = if address.starts_with(UNIX_PREFIX) {
+            let path = PathBuf::from(&address[UNIX_PREFIX.len()..]);
+            try!(UnixListener::bind(&path).map(|l| Listener::Unix(l, path)).map_err(&bind_error))
+        } else {
+            try!(TcpListener::bind(address).map(Listener::Tcp).map_err(&bind_error))
+        };
+        let (event_sender, events) = channel();
+        Ok(ServerAdapter{
+            address: address.to_string(),
+            listener: Some(listener),
+            accepting: None,
+            events: events,
+            event_sender: event_sender,
+            pending: BTreeMap::new(),
+            next_request: 0,
+            closed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+impl ChatAdapter for ServerAdapter {
+    fn name(&self) -> &'static str {
+        "Server"
+    }
+
+    fn connect(&mut self) -> Result<(), AdapterError> {
+        // Accepting goes on between connections, so it only has to start once
+        if let Some(listener) = self.listener.take() {
+            let (events, closed) = (self.event_sender.clone(), self.closed.clone());
+            self.accepting = Some(thread::spawn(move || accept(listener, events, closed)));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Option<Duration>) -> Result<Option<Incoming>, AdapterError> {
+        match recv_within(&self.events, timeout) {
+            Ok(Some(Event::Request(mut incoming, pending))) => {
+                if let Some(pending) = pending {
+                    self.next_request += 1;
+                    self.pending.insert(self.next_request, pending);
+                    incoming.target = self.next_request.to_string();
+                }
+                Ok(Some(incoming))
+            },
+            Ok(Some(Event::Wake)) => Err(AdapterError::Disconnected("Woken".to_string())),
+            Ok(None) => Ok(None),
+            Err(_) => Err(AdapterError::Disconnected("Listener stopped".to_string())),
+        }
+    }
+
+    fn send(&mut self, target: &str, reply: Option<&str>) -> Result<(), AdapterError> {
+        let pending = try!(target.parse().ok().and_then(|id| self.pending.remove(&id))
+            .ok_or_else(|| AdapterError::Send(format!("No request `{}`", target))));
+        respond(&pending.writer, Response{
+            id: pending.id,
+            reply: reply.map(|s| s.to_string()),
+            error: None,
+        }).map_err(AdapterError::Send)
+    }
+
+    fn shutdown(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        // Accepting blocks, so give it a client to notice with, then wait for the address to be free again
+        let woken = if self.address.starts_with(UNIX_PREFIX) {
+            UnixStream::connect(&self.address[UNIX_PREFIX.len()..]).is_ok()
+        } else {
+            TcpStream::connect(self.address.as_str()).is_ok()
+        };
+        if let (true, Some(accepting)) = (woken, self.accepting.take()) {
+            let _ = accepting.join();
+        }
+    }
+
+    // Each request is answered once
+    fn initiates(&self) -> bool {
+        false
+    }
+
+    fn waker(&self) -> Option<Box<Fn() + Send>> {
+        let events = self.event_sender.clone();
+        Some(Box::new(move || {
+            let _ = events.send(Event::Wake);
+        }))
+    }
+}
+
+fn accept(listener: Listener, events: Sender<Event>, closed: Arc<AtomicBool>) {
+    match listener {
+        Listener::Tcp(listener) => {
+            for stream in listener.incoming() {
+                if closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream.and_then(|s| s.try_clone().map(|w| (s, w))) {
+                    Ok((stream, writer)) => serve(stream, Box::new(writer), events.clone(), closed.clone()),
+                    Err(e) => println!("Warning: Unable to accept server connection: {}", e),
+                }
             }
-            Err(e) => {
-                println!("{:?}", e);
+        },
+        Listener::Unix(listener, path) => {
+            for stream in listener.incoming() {
+                if closed.load(Ordering::SeqCst) {
+                    break;
+                }
+                match stream.and_then(|s| s.try_clone().map(|w| (s, w))) {
+                    Ok((stream, writer)) => serve(stream, Box::new(writer), events.clone(), closed.clone()),
+                    Err(e) => println!("Warning: Unable to accept server connection: {}", e),
+                }
             }
-        }
+            // Nothing else removes the socket file, and it would stop the address being bound again
+            let _ = fs::remove_file(path);
+        },
     }
-}
\ No newline at end of file
+}
+
+/// Read requests from a client in a new thread, passing them on to the adapter.
+fn serve<S>(stream: S, writer: Box<Write + Send>, events: Sender<Event>, closed: Arc<AtomicBool>)
+    where S: Read + Send + 'static
+{
+    let writer = Arc::new(Mutex::new(writer));
+    thread::spawn(move || {
+        let reader = BufReader::new(stream);
+        for line in reader.lines() {
+            if closed.load(Ordering::SeqCst) {
+                break;
+            }
+            let line = match line {
+                Ok(line) => line,
+                Err(_) => break,
+            };
+            let event = serde_json::from_str::<Request>(&line)
+                .map_err(|e| {
+                    println!("Error parsing json: {:?}", e);
+                    (None, format!("Invalid request: {}", e))
+                })
+                .and_then(|request| event(request, &writer));
+            let result = match event {
+                Ok(event) => events.send(event).map_err(|_| "Server stopped".to_string()),
+                Err((id, e)) => respond(&writer, Response{
+                    id: id,
+                    reply: None,
+                    error: Some(e),
+                }),
+            };
+            if let Err(e) = result {
+                println!("Warning: Unable to handle server request: {}", e);
+                break;
+            }
+        }
+    });
+}
+
+/// Turn a request into an event, saying where its response goes if it wants one.
+fn event(request: Request, writer: &Writer) -> Result<Event, (Option<Value>, String)> {
+    let respond_only = match request.kind.as_ref().map(|s| s.as_str()) {
+        None | Some("tell") => false,
+        Some("respond") => true,
+        Some(kind) => return Err((request.id.clone(), format!("Unrecognized request type `{}`", kind))),
+    };
+    let wants_reply = respond_only || request.reply.unwrap_or(false) || request.id.is_some();
+    let incoming = Incoming{
+        message: ChatMessage{
+            source: request.source,
+            author: request.author,
+            // The bot only responds to an empty message
+            message: if respond_only { String::new() } else { request.message },
+        },
+        target: String::new(),
+        wants_reply: wants_reply,
+        identity: None,
+    };
+    let pending = if wants_reply {
+        Some(Pending{
+            writer: writer.clone(),
+            id: request.id,
+        })
+    } else {
+        None
+    };
+    Ok(Event::Request(incoming, pending))
+}
+
+fn respond(writer: &Writer, response: Response) -> Result<(), String> {
+    let s = try!(serde_json::to_string(&response).map_err(|e| e.to_string()));
+    let mut writer = try!(writer.lock().map_err(|_| "Writer poisoned".to_string()));
+    writeln!(writer, "{}", s).map_err(|e| format!("Unable to write server response: {}", e))
+}
@@ -0,0 +1,298 @@
+extern crate websocket;
+extern crate serde_json;
+
+use self::websocket::{Message, Receiver as WebSocketReceiver, Sender as WebSocketSender, Server};
+use self::websocket::client;
+use self::websocket::message::Type;
+use self::websocket::server::Connection;
+use self::websocket::stream::WebSocketStream;
+use std::collections::BTreeMap;
+use std::net::TcpStream;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use chat::{recv_within, AdapterError, ChatAdapter, ChatMessage, Incoming};
+
+/// Address listened on when none is given
+pub const DEFAULT_ADDRESS: &'static str = "127.0.0.1:2936";
+/// Author of messages that don't say who sent them
+const DEFAULT_AUTHOR: &'static str = "guest";
+/// Author of the bot's replies, which also asks for a reply when mentioned
+const BOT_AUTHOR: &'static str = "seifmios";
+
+/// A frame sent as JSON rather than plain text. A frame with a room moves the connection into that room, which is
+/// shared by every connection in it.
+#[derive(Deserialize, Debug)]
+struct Frame {
+    room: Option<String>,
+    author: Option<String>,
+    message: Option<String>,
+    /// True to ask for a reply in a room, where whether to reply is otherwise up to its policy
+    reply: Option<bool>,
+}
+
+/// What the other connections in a room are sent when someone, or the bot, says something there
+#[derive(Serialize, Debug)]
+struct Said {
+    author: String,
+    message: String,
+}
+
+/// Where replies to a connection go
+type Frames = Arc<Mutex<client::Sender<WebSocketStream>>>;
+
+/// A connection and the source it is in
+struct Member {
+    frames: Frames,
+    source: String,
+}
+
+enum Event {
+    Connected(u64, Frames),
+    /// A connection moved into a room, given by its source
+    Joined(u64, String),
+    Disconnected(u64),
+    /// A message, the connection it came from and whether it asked for a reply
+    Message(ChatMessage, u64, bool),
+    /// Makes `receive` return so a stop is noticed
+    Wake,
+}
+
+/// Each browser connection is its own source until it joins a room. Messages in a room are passed on to the other
+/// connections in it, and the bot's replies go to every connection in the room, all as JSON frames with their author.
+/// Everything said to a connection on its own asks for a reply, but in a room only mentions and frames that ask do.
+pub struct WebSocketAdapter {
+    address: String,
+    // Taken when accepting starts
+    server: Option<Server<'static>>,
+    accepting: Option<JoinHandle<()>>,
+    events: Receiver<Event>,
+    event_sender: Sender<Event>,
+    connections: BTreeMap<u64, Member>,
+    closed: Arc<AtomicBool>,
+}
+
+impl WebSocketAdapter {
+    /// Bind the gateway's address. This is done before starting the adapter so a failure can be reported.
+    pub fn bind(address: &str) -> Result<WebSocketAdapter, AdapterError> {
+        let server = try!(Server::bind(address)
+            .map_err(|e| AdapterError::Connect(format!("Unable to bind `{}`: {}", address, e))));
+        let (event_sender, events) = channel();
+        Ok(WebSocketAdapter{
+            address: address.to_string(),
+            server: Some(server),
+            accepting: None,
+            events: events,
+            event_sender: event_sender,
+            connections: BTreeMap::new(),
+            closed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Send a frame to every connection in a source but `except`. A connection that can't be sent to is about
+    /// to be disconnected, so it doesn't stop the rest.
+    fn broadcast(&self, source: &str, except: Option<u64>, said: &Said) {
+        let text = match serde_json::to_string(said) {
+            Ok(text) => text,
+            Err(e) => {
+                println!("Warning: Unable to encode WebSocket message: {}", e);
+                return;
+            },
+        };
+        for (&id, member) in &self.connections {
+            if member.source != source || Some(id) == except {
+                continue;
+            }
+            let sent = match member.frames.lock() {
+                Ok(mut frames) => frames.send_message(&Message::text(text.clone())).map_err(|e| e.to_string()),
+                Err(_) => Err("Frames poisoned".to_string()),
+            };
+            if let Err(e) = sent {
+                println!("Warning: Unable to send WebSocket frame to connection {}: {}", id, e);
+            }
+        }
+    }
+}
+
+impl ChatAdapter for WebSocketAdapter {
+    fn name(&self) -> &'static str {
+        "WebSocket"
+    }
+
+    fn nick(&self) -> Option<String> {
+        Some(BOT_AUTHOR.to_string())
+    }
+
+    fn connect(&mut self) -> Result<(), AdapterError> {
+        // Accepting goes on between connections, so it only has to start once
+        if let Some(server) = self.server.take() {
+            let (events, closed) = (self.event_sender.clone(), self.closed.clone());
+            self.accepting = Some(thread::spawn(move || accept(server, events, closed)));
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self, timeout: Option<Duration>) -> Result<Option<Incoming>, AdapterError> {
+        match recv_within(&self.events, timeout) {
+            Ok(Some(Event::Connected(id, frames))) => {
+                self.connections.insert(id, Member{
+                    frames: frames,
+                    source: source(id),
+                });
+                Ok(None)
+            },
+            Ok(Some(Event::Joined(id, source))) => {
+                if let Some(member) = self.connections.get_mut(&id) {
+                    member.source = source;
+                }
+                Ok(None)
+            },
+            Ok(Some(Event::Disconnected(id))) => {
+                self.connections.remove(&id);
+                Ok(None)
+            },
+            Ok(Some(Event::Message(message, id, wants_reply))) => {
+                self.broadcast(&message.source, Some(id), &Said{
+                    author: message.author.clone(),
+                    message: message.message.clone(),
+                });
+                // Replies go to the whole room, so the room is the target
+                Ok(Some(Incoming{
+                    target: message.source.clone(),
+                    message: message,
+                    wants_reply: wants_reply,
+                    identity: None,
+                }))
+            },
+            Ok(Some(Event::Wake)) => Err(AdapterError::Disconnected("Woken".to_string())),
+            Ok(None) => Ok(None),
+            Err(_) => Err(AdapterError::Disconnected("Gateway stopped".to_string())),
+        }
+    }
+
+    fn send(&mut self, target: &str, reply: Option<&str>) -> Result<(), AdapterError> {
+        let reply = match reply {
+            Some(reply) => reply,
+            None => return Ok(()),
+        };
+        // Everyone may have left while the bot was thinking
+        self.broadcast(target, None, &Said{
+            author: BOT_AUTHOR.to_string(),
+            message: reply.to_string(),
+        });
+        Ok(())
+    }
+
+    fn shutdown(&mut self) {
+        self.closed.store(true, Ordering::SeqCst);
+        for member in self.connections.values() {
+            if let Ok(mut frames) = member.frames.lock() {
+                let _ = frames.send_message(&Message::close());
+            }
+        }
+        // Accepting blocks, so give it a client to notice with, then wait for the address to be free again
+        if let (true, Some(accepting)) = (TcpStream::connect(self.address.as_str()).is_ok(), self.accepting.take()) {
+            let _ = accepting.join();
+        }
+    }
+
+    fn waker(&self) -> Option<Box<Fn() + Send>> {
+        let events = self.event_sender.clone();
+        Some(Box::new(move || {
+            let _ = events.send(Event::Wake);
+        }))
+    }
+}
+
+/// The source a connection is in before it joins a room
+fn source(id: u64) -> String {
+    format!("websocket:{}", id)
+}
+
+fn accept(server: Server<'static>, events: Sender<Event>, closed: Arc<AtomicBool>) {
+    let mut connections = 0;
+    for connection in server {
+        if closed.load(Ordering::SeqCst) {
+            break;
+        }
+        match connection {
+            Ok(connection) => {
+                connections += 1;
+                let id = connections;
+                let (events, closed) = (events.clone(), closed.clone());
+                thread::spawn(move || {
+                    serve(connection, id, &events, closed).unwrap_or_else(|e| {
+                        println!("Warning: WebSocket connection ended: {}", e);
+                    });
+                    let _ = events.send(Event::Disconnected(id));
+                });
+            },
+            Err(e) => println!("Warning: Unable to accept WebSocket connection: {}", e),
+        }
+    }
+}
+
+fn serve(connection: Connection<WebSocketStream, WebSocketStream>, id: u64, events: &Sender<Event>,
+    closed: Arc<AtomicBool>) -> Result<(), String>
+{
+    let request = try!(connection.read_request().map_err(|e| format!("Invalid handshake: {}", e)));
+    try!(request.validate().map_err(|e| format!("Invalid handshake: {}", e)));
+    let client = try!(request.accept().send().map_err(|e| format!("Unable to accept: {}", e)));
+    let (frames, mut incoming) = client.split();
+    let frames = Arc::new(Mutex::new(frames));
+    try!(events.send(Event::Connected(id, frames.clone())).map_err(|_| "Gateway stopped".to_string()));
+    let own = source(id);
+    let mut source = own.clone();
+
+    for message in incoming.incoming_messages() {
+        if closed.load(Ordering::SeqCst) {
+            break;
+        }
+        let message: Message = try!(message.map_err(|e| format!("Unable to read frame: {}", e)));
+        let text = match message.opcode {
+            Type::Text => try!(str::from_utf8(&message.payload).map_err(|e| format!("Invalid text: {}", e))).to_string(),
+            Type::Ping => {
+                let mut frames = try!(frames.lock().map_err(|_| "Frames poisoned".to_string()));
+                try!(frames.send_message(&Message::pong(message.payload)).map_err(|e| e.to_string()));
+                continue;
+            },
+            Type::Close => {
+                if let Ok(mut frames) = frames.lock() {
+                    let _ = frames.send_message(&Message::close());
+                }
+                break;
+            },
+            _ => continue,
+        };
+
+        let (author, text, asked) = match serde_json::from_str::<Frame>(&text) {
+            Ok(frame) => {
+                if let Some(room) = frame.room {
+                    source = format!("websocket:room:{}", room);
+                    try!(events.send(Event::Joined(id, source.clone())).map_err(|_| "Gateway stopped".to_string()));
+                }
+                match frame.message {
+                    Some(m) => {
+                        (frame.author.unwrap_or_else(|| DEFAULT_AUTHOR.to_string()), m, frame.reply.unwrap_or(false))
+                    },
+                    // Only joining a room
+                    None => continue,
+                }
+            },
+            // Anything else is just a message
+            Err(_) => (DEFAULT_AUTHOR.to_string(), text, false),
+        };
+        // Nobody else is there to talk to on a connection's own
+        let wants_reply = asked || source == own;
+        let chat_message = ChatMessage{
+            source: source.clone(),
+            author: author,
+            message: text,
+        };
+        try!(events.send(Event::Message(chat_message, id, wants_reply)).map_err(|_| "Gateway stopped".to_string()));
+    }
+    Ok(())
+}
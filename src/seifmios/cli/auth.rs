@@ -0,0 +1,79 @@
+use std::env;
+
+/// Environment variable holding the token that allows every command
+pub const ADMIN_TOKEN_VAR: &'static str = "SEIFMIOS_ADMIN_TOKEN";
+/// Environment variable holding the token that only allows commands which don't change anything
+pub const READ_TOKEN_VAR: &'static str = "SEIFMIOS_READ_TOKEN";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    None,
+    Read,
+    Admin,
+}
+
+impl Permission {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Permission::None => "none",
+            Permission::Read => "read",
+            Permission::Admin => "admin",
+        }
+    }
+
+    /// The permission needed to run a command
+    pub fn required(command: &str) -> Permission {
+        match command {
+            "help" | "auth" => Permission::None,
+            "get" | "stats" | "find" | "list" | "jobs" | "respond" | "connections" => Permission::Read,
+            // Everything else changes the bot or touches files on the server
+            _ => Permission::Admin,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    pub admin_token: Option<String>,
+    pub read_token: Option<String>,
+}
+
+impl Auth {
+    /// Tokens in the environment take the place of the given ones.
+    pub fn new(admin_token: Option<String>, read_token: Option<String>) -> Auth {
+        Auth{
+            admin_token: env::var(ADMIN_TOKEN_VAR).ok().or(admin_token),
+            read_token: env::var(READ_TOKEN_VAR).ok().or(read_token),
+        }
+    }
+
+    /// What clients can do without a token. With no admin token everything is open like it always was, and
+    /// with no read token the read-only commands are.
+    pub fn unauthenticated(&self) -> Permission {
+        match (&self.admin_token, &self.read_token) {
+            (&None, _) => Permission::Admin,
+            (&Some(_), &None) => Permission::Read,
+            (&Some(_), &Some(_)) => Permission::None,
+        }
+    }
+
+    /// Get the permission a token grants, if it grants any.
+    pub fn check(&self, token: &str) -> Option<Permission> {
+        if self.admin_token.as_ref().map(|t| same(t, token)).unwrap_or(false) {
+            Some(Permission::Admin)
+        } else if self.read_token.as_ref().map(|t| same(t, token)).unwrap_or(false) {
+            Some(Permission::Read)
+        } else {
+            None
+        }
+    }
+}
+
+/// Compare tokens without stopping at the first difference so the time taken doesn't give away how much matched
+fn same(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -1,8 +1,10 @@
+use std::cmp;
 use std::thread::{JoinHandle, spawn};
 use std::sync::mpsc::{Receiver, Sender, TryRecvError, channel};
 
 extern crate serde_json;
 use self::serde_json::de::from_str;
+use self::serde_json::ser::to_string;
 
 extern crate zmq;
 use self::zmq::Socket;
@@ -10,37 +12,129 @@ use self::zmq::Socket;
 #[path = "../../shared/ipc.rs"]
 mod ipc;
 
+pub use self::ipc::{endpoint, Reply, STATUS_OK};
+
+pub mod auth;
+use self::auth::{Auth, Permission};
+
 extern crate either;
 
+use super::config::LexiconConfig;
+use super::text::{GraphFormat, MessageFilter, MessageFormat};
+use super::import;
+use super::import::irclog;
+use super::import::json::FieldMap;
+use super::import::job::Kind;
+
+enum Target {
+    Zmq {
+        socket: Option<Socket>,
+        socket_return: Sender<Socket>,
+        // Which client the router should send replies to
+        identity: Vec<u8>,
+    },
+    // Replies are gathered and sent back together when the response ends, for commands that didn't come over zmq
+    Collect {
+        replies: Vec<Reply>,
+        done: Sender<Vec<Reply>>,
+    },
+}
+
 pub struct SocketLend {
-    socket: Option<Socket>,
-    socket_return: Sender<Socket>,
+    target: Target,
+    // Code of the first error sent in this response
+    error: Option<String>,
 }
 
 impl SocketLend {
+    /// Make a lend that gathers its replies, along with where they all go once it is dropped.
+    fn collect() -> (SocketLend, Receiver<Vec<Reply>>) {
+        let (done, replies) = channel();
+        let lend = SocketLend{
+            target: Target::Collect{
+                replies: Vec::new(),
+                done: done,
+            },
+            error: None,
+        };
+        (lend, replies)
+    }
+
+    fn send(&mut self, reply: Reply) {
+        match self.target {
+            Target::Zmq{ref mut socket, ref identity, ..} => {
+                let s = to_string(&reply).unwrap_or_else(|e| panic!("Error: Unable to encode reply: {}", e));
+                let socket = socket.as_mut().unwrap();
+                socket.send(identity, zmq::SNDMORE)
+                    .and_then(|_| socket.send_str(&s, 0))
+                    .unwrap_or_else(|e| {
+                        println!("Warning: Unable to send message over return socket: {}", e);
+                    });
+            },
+            Target::Collect{ref mut replies, ..} => replies.push(reply),
+        }
+    }
+
     pub fn msg(&mut self, m: &str) {
-        self.socket.as_mut().unwrap().send_str(m, 0).unwrap_or_else(|e| {
-            println!("Warning: Unable to send message over return socket: {}", e);
-        });
+        self.send(Reply::ok(m));
+    }
+
+    /// Tell the client the command went wrong. `code` is a short machine-readable reason like `usage`.
+    pub fn error(&mut self, code: &str, m: &str) {
+        if self.error.is_none() {
+            self.error = Some(code.to_string());
+        }
+        self.send(Reply::error(code, m));
     }
 }
 
 impl Drop for SocketLend {
     fn drop(&mut self) {
-        self.msg("");
-        self.socket_return.send(self.socket.take().unwrap()).unwrap_or_else(|e| {
-            println!("Warning: Failed to return socket; cli now unavailable: {}", e);
-        });
+        let end = Reply::end(self.error.take());
+        self.send(end);
+        match self.target {
+            Target::Zmq{ref mut socket, ref socket_return, ..} => {
+                socket_return.send(socket.take().unwrap()).unwrap_or_else(|e| {
+                    println!("Warning: Failed to return socket; cli now unavailable: {}", e);
+                });
+            },
+            Target::Collect{ref mut replies, ref done} => {
+                // Whoever was waiting may have given up
+                let _ = done.send(replies.drain(..).collect());
+            },
+        }
     }
 }
 
 pub enum Decision {
     Quit,
-    ImportLines(String),
+    Import(Kind, String),
+    Jobs,
+    Cancel(usize),
     ShowCategories,
+    Stats,
+    Save(Option<String>),
+    Reload,
+    PresetSave(String),
+    PresetLoad(String),
+    PresetList,
+    GetAll,
     Respond,
     Tell(String),
-    ConnectServer,
+    /// Tell a message from anywhere, with its source, author and message
+    TellTo(String, String, String),
+    /// Respond in a source, first telling it a prompt from an author if there is one
+    RespondTo(String, Option<(String, String)>),
+    /// Forget the last thing said in a source, or the console, also removing the messages it was made from if set
+    ForgetLast(Option<String>, bool),
+    GetParams,
+    SetParams(LexiconConfig),
+    ConnectHttp(Option<String>),
+    Connections,
+    Disconnect(usize),
+    Reconnect(usize),
+    ConnectWebSocket(Option<String>),
+    ConnectServer(Option<String>),
     ConnectIrc(String),
     ConnectDiscord(String),
     SetCocategoryRatio(f64),
@@ -58,419 +152,830 @@ pub enum Decision {
     SetBackwardWordDistance(usize),
     GetBackwardWordDistance,
     FindRelation((String, String)),
+    FindWord((String, usize)),
+    ExportGraph(String, GraphFormat, usize, Option<(String, usize)>),
+    ExportMessages(String, MessageFormat, MessageFilter),
 }
 
-pub fn new() -> Iter {
+/// Listen for commands on a zmq endpoint such as `ipc:///tmp/seifmios_cli_commands.ipc` or `tcp://127.0.0.1:2934`.
+/// Commands a client doesn't have permission for are rejected before they are ever seen by the main loop.
+///
+/// A command may be sent with a token in the frame before it. Nothing is remembered between commands, since the
+/// identity the router sees is chosen by the client and could be reused by anyone.
+pub fn new(endpoint: String, auth: Auth) -> Iter {
     let (sender, receiver) = channel();
+    let (decision_sender, decisions) = channel();
     let (socket_return, socket_receiver) = channel();
     Iter{
         _thread: spawn(move || {
             let mut context = zmq::Context::new();
-            // Create pair socket which allows only one connection at a time
-            let mut socket = context.socket(zmq::PAIR)
+            // Create router socket so any number of clients can queue up commands; replies go back to whichever
+            // client sent the command
+            let mut socket = context.socket(zmq::ROUTER)
                 .unwrap_or_else(|e| panic!("Error: Failed to open zmq socket: {}", e));
-            // Attempt to bind ipc file to socket
-            socket.bind(ipc::PATH).unwrap_or_else(|e| panic!("Error: Failed to connect to ipc file: {}", e));
+            // Attempt to bind the endpoint to the socket
+            socket.bind(&endpoint)
+                .unwrap_or_else(|e| panic!("Error: Failed to bind cli endpoint `{}`: {}", endpoint, e));
             // Receive commands
             loop {
-                match socket.recv_string(0) {
-                    Ok(m) => {
-                        match m {
-                            Ok(s) => {
-                                // Parse JSON into string vector
-                                let v = match from_str::<Vec<String>>(&s) {
-                                    Ok(v) => v,
-                                    Err(e) => {
-                                        println!("Ignored: Unable to parse cli command from JSON: {}", e);
-                                        continue;
-                                    },
+                match socket.recv_multipart(0) {
+                    Ok(mut parts) => {
+                        // Routers put the identity of the client first, then comes the token if there is one
+                        if parts.len() != 2 && parts.len() != 3 {
+                            println!("Ignored: Got cli command with {} parts", parts.len());
+                            continue;
+                        }
+                        let m = String::from_utf8(parts.pop().unwrap());
+                        let token = if parts.len() == 2 { Some(parts.pop().unwrap()) } else { None };
+                        let identity = parts.pop().unwrap();
+                        let lend = SocketLend{
+                            target: Target::Zmq{
+                                socket: Some(socket),
+                                socket_return: socket_return.clone(),
+                                identity: identity.clone(),
+                            },
+                            error: None,
+                        };
+                        // Parse JSON into string vector
+                        match m.map(|s| from_str::<Vec<String>>(&s)) {
+                            Ok(Ok(v)) => {
+                                let mut lend = lend;
+                                let permission = match token {
+                                    Some(t) => String::from_utf8(t).ok()
+                                        .and_then(|t| auth.check(&t))
+                                        .map(|p| cmp::max(p, auth.unauthenticated())),
+                                    None => Some(auth.unauthenticated()),
                                 };
-                                // Send the command vector along with the socket
-                                match sender.send((v, SocketLend{
-                                    socket: Some(socket),
-                                    socket_return: socket_return.clone(),
-                                })) {
-                                    Ok(_) => {},
-                                    Err(e) => panic!("Error: IPC thread unable to send: {}", e),
+                                let needed = v.get(0).map(|c| Permission::required(c)).unwrap_or(Permission::None);
+                                match permission {
+                                    None => {
+                                        println!("Warning: Rejected cli command with an invalid token");
+                                        lend.error("unauthorized", "Ignored: Invalid token");
+                                    },
+                                    // Lets a client find out whether a token is any good before sending it with
+                                    // every command
+                                    Some(_) if v.get(0).map(|c| c == "auth").unwrap_or(false) => {
+                                        if v.len() != 2 {
+                                            lend.error("usage", "Usage: auth <token>");
+                                        } else {
+                                            match auth.check(&v[1]) {
+                                                Some(p) => {
+                                                    lend.msg(&format!("Token has {} permission", p.name()));
+                                                },
+                                                None => {
+                                                    println!("Warning: Rejected cli authentication with an invalid token");
+                                                    lend.error("unauthorized", "Ignored: Invalid token");
+                                                },
+                                            }
+                                        }
+                                    },
+                                    Some(permission) if permission < needed => {
+                                        println!("Warning: Rejected cli command `{}` from a client with {} permission",
+                                            v[0], permission.name());
+                                        lend.error("permission_denied", &format!("Ignored: `{}` needs {} permission",
+                                            v[0], needed.name()));
+                                    },
+                                    Some(_) => {
+                                        // Send the command vector along with the socket
+                                        match sender.send((v, lend)) {
+                                            Ok(_) => {},
+                                            Err(e) => panic!("Error: IPC thread unable to send: {}", e),
+                                        }
+                                    },
                                 }
                             },
-                            Err(_) => println!("Ignored: Unable to parse cli command from JSON"),
+                            // Dropping the lend lets the client know it was ignored and returns the socket
+                            Ok(Err(e)) => {
+                                let mut lend = lend;
+                                lend.error("invalid_command", &format!("Ignored: Unable to parse cli command from JSON: {}", e));
+                            },
+                            Err(_) => {
+                                let mut lend = lend;
+                                lend.error("invalid_command", "Ignored: Unable to parse cli command from JSON");
+                            },
                         }
                     },
-                    Err(e) => println!("Warning: Failed to get command: {}", e),
+                    Err(e) => {
+                        println!("Warning: Failed to get command: {}", e);
+                        // The socket was never lent out, so there is nothing to wait for
+                        continue;
+                    },
                 }
                 socket = socket_receiver.recv().unwrap_or_else(|e| panic!("Fatal: Unable to retrieve socket: {}", e));
             }
         }),
         receiver: receiver,
+        decisions: decisions,
+        decision_sender: decision_sender,
     }
 }
 
 pub struct Iter {
     _thread: JoinHandle<()>,
     receiver: Receiver<(Vec<String>, SocketLend)>,
+    decisions: Receiver<(Decision, SocketLend)>,
+    decision_sender: Sender<(Decision, SocketLend)>,
+}
+
+impl Iter {
+    /// Get a way to make decisions without going through zmq, for other interfaces like the HTTP API.
+    pub fn commands(&self) -> Commands {
+        Commands(self.decision_sender.clone())
+    }
+}
+
+/// Sends decisions straight to the main loop. Permissions aren't checked, so that is up to whoever uses it.
+#[derive(Clone)]
+pub struct Commands(Sender<(Decision, SocketLend)>);
+
+impl Commands {
+    /// Commands that go to the returned receiver instead of a main loop, so what uses them can be tested alone
+    #[cfg(test)]
+    pub fn with_receiver() -> (Commands, Receiver<(Decision, SocketLend)>) {
+        let (sender, receiver) = channel();
+        (Commands(sender), receiver)
+    }
+
+    /// Make a decision and wait for every reply to it, ending with the `end` reply.
+    pub fn run(&self, decision: Decision) -> Result<Vec<Reply>, String> {
+        let (lend, replies) = SocketLend::collect();
+        try!(self.0.send((decision, lend)).map_err(|_| "Main loop has stopped".to_string()));
+        replies.recv().map_err(|_| "Main loop dropped the command".to_string())
+    }
+
+    /// Parse a command the way the cli does, then make the decision and wait for every reply to it. A command that
+    /// can't be parsed only gets the replies saying why.
+    pub fn run_command(&self, params: Vec<String>) -> Result<Vec<Reply>, String> {
+        let (lend, replies) = SocketLend::collect();
+        if let Some(decision) = parse(params, lend) {
+            try!(self.0.send(decision).map_err(|_| "Main loop has stopped".to_string()));
+        }
+        replies.recv().map_err(|_| "Main loop dropped the command".to_string())
+    }
 }
 
 impl Iterator for Iter {
     type Item = Option<(Decision, SocketLend)>;
 
     fn next(&mut self) -> Option<Self::Item> {
+        if let Ok(decision) = self.decisions.try_recv() {
+            return Some(Some(decision));
+        }
         match self.receiver.try_recv() {
-            Ok((params, mut socket)) => {
-                // let socket_fail = || panic!("Warning: Failed to respond to command");
+            Ok((params, socket)) => Some(parse(params, socket)),
+            Err(TryRecvError::Empty) => {
+                Some(None)
+            },
+            Err(TryRecvError::Disconnected) => {
+                None
+            },
+        }
+    }
+}
 
-                let help = |s: &mut SocketLend| {
-                    s.msg("Available commands: quit, import, connect, list, respond, tell, get, set, find");
-                };
+/// Turn a command into a decision, telling whoever sent it what was wrong if it can't be.
+fn parse(params: Vec<String>, mut socket: SocketLend) -> Option<(Decision, SocketLend)> {
+    // let socket_fail = || panic!("Warning: Failed to respond to command");
 
-                match params.len() {
-                    0 => {
-                        help(&mut socket);
-                        Some(None)
-                    },
-                    _ => {
-                        match &*params[0] {
-                            "help" => {
-                                help(&mut socket);
-                                Some(None)
-                            },
-                            "quit" => {
-                                if params.len() != 1 {
-                                    socket.msg("Usage: quit");
-                                    Some(None)
+    let help = |s: &mut SocketLend| {
+        s.msg("Available commands: auth, quit, import, jobs, cancel, export, connect, connections, disconnect, reconnect, list, stats, save, reload, preset, respond, tell, forget, get, set, find");
+    };
+
+    match params.len() {
+        0 => {
+            help(&mut socket);
+            None
+        },
+        _ => {
+            match &*params[0] {
+                "help" => {
+                    help(&mut socket);
+                    None
+                },
+                "quit" => {
+                    if params.len() != 1 {
+                        socket.error("usage", "Usage: quit");
+                        None
+                    } else {
+                        Some((Decision::Quit, socket))
+                    }
+                },
+                "import" => {
+                    if params.len() < 2 {
+                        socket.error("usage", "Usage: import <import type>");
+                        socket.msg("Available import types: lines, irclog, discord-export, jsonl");
+                        None
+                    } else {
+                        match &*params[1] {
+                            "lines" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: import lines <filname>");
+                                    None
                                 } else {
-                                    Some(Some((Decision::Quit, socket)))
+                                    socket.msg(&format!("Importing lines from `{}`...", params[2]));
+                                    Some((Decision::Import(Kind::Lines, params[2].to_string()), socket))
                                 }
                             },
-                            "import" => {
-                                if params.len() < 2 {
-                                    socket.msg("Usage: import <import type>");
-                                    socket.msg("Available import types: lines");
-                                    Some(None)
+                            "discord-export" | "jsonl" => {
+                                if params.len() < 3 {
+                                    socket.error("usage", &format!("Usage: import {} <filename> [<field>=<path> ...]", params[1]));
+                                    socket.msg("Fields: source, author, content, timestamp");
+                                    None
                                 } else {
-                                    match &*params[1] {
-                                        "lines" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: import lines <filname>");
-                                                Some(None)
-                                            } else {
-                                                socket.msg(&format!("Importing lines from `{}`...", params[2]));
-                                                Some(Some((Decision::ImportLines(params[2].to_string()), socket)))
-                                            }
-                                        },
-                                        _ => {
-                                            socket.msg("Ignored: Unrecognized import type");
-                                            Some(None)
-                                        },
+                                    let mut fields = if params[1] == "jsonl" {
+                                        FieldMap::jsonl()
+                                    } else {
+                                        FieldMap::discord_export()
+                                    };
+                                    for assignment in &params[3..] {
+                                        if let Err(e) = fields.set(assignment) {
+                                            socket.error("invalid_value", &format!("Ignored: {}", e));
+                                            return None;
+                                        }
+                                    }
+                                    socket.msg(&format!("Importing {} from `{}`...", params[1], params[2]));
+                                    if params[1] == "jsonl" {
+                                        Some((Decision::Import(Kind::JsonLines(fields), params[2].to_string()), socket))
+                                    } else {
+                                        Some((Decision::Import(Kind::DiscordExport(fields), params[2].to_string()), socket))
                                     }
                                 }
                             },
-                            "set" => {
-                                if params.len() < 2 {
-                                    socket.msg("Usage: set <value>");
-                                    socket.msg("Values: cc_ratio, cc_travel, cc_mag, fw_edge, bw_edge, fw_word, bw_word");
-                                    Some(None)
+                            "irclog" => {
+                                if params.len() != 4 && params.len() != 5 {
+                                    socket.error("usage", "Usage: import irclog <irssi|weechat|znc> <filename> [gap minutes]");
+                                    None
                                 } else {
-                                    match &*params[1] {
-                                        "cc_ratio" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: set cc_ratio <ratio>");
-                                                Some(None)
-                                            } else {
-                                                match params[2].parse::<f64>() {
-                                                    Ok(f) => {
-                                                        Some(Some((Decision::SetCocategoryRatio(f), socket)))
-                                                    },
-                                                    Err(e) => {
-                                                        socket.msg(&format!("Ignored: Error converting value: {}\n", e));
-                                                        Some(None)
-                                                    },
-                                                }
-                                            }
+                                    let gap = match params.get(4).map(|g| g.parse::<u64>()) {
+                                        Some(Ok(minutes)) => minutes * 60,
+                                        Some(Err(e)) => {
+                                            socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                            return None;
                                         },
-                                        "cc_travel" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: set cc_travel <steps>");
-                                                Some(None)
-                                            } else {
-                                                match params[2].parse::<i32>() {
-                                                    Ok(steps) => {
-                                                        Some(Some((Decision::SetTravelDistance(steps), socket)))
-                                                    },
-                                                    Err(e) => {
-                                                        socket.msg(&format!("Ignored: Error converting value: {}\n", e));
-                                                        Some(None)
-                                                    },
-                                                }
-                                            }
+                                        None => import::CONVERSATION_GAP,
+                                    };
+                                    match irclog::Format::parse(&params[2]) {
+                                        Some(format) => {
+                                            socket.msg(&format!("Importing IRC log from `{}`...", params[3]));
+                                            Some((Decision::Import(Kind::IrcLog(format, gap), params[3].to_string()), socket))
                                         },
-                                        "cc_mag" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: set cc_mag <cycles>");
-                                                Some(None)
-                                            } else {
-                                                match params[2].parse::<i32>() {
-                                                    Ok(steps) => {
-                                                        Some(Some((Decision::SetCocategorizeMagnitude(steps), socket)))
-                                                    },
-                                                    Err(e) => {
-                                                        socket.msg(&format!("Ignored: Error converting value: {}\n", e));
-                                                        Some(None)
-                                                    },
-                                                }
-                                            }
+                                        None => {
+                                            socket.error("unrecognized", "Ignored: Unrecognized IRC log format");
+                                            None
                                         },
-                                        "fw_edge" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: set fw_edge <distance>");
-                                                Some(None)
-                                            } else {
-                                                match params[2].parse::<usize>() {
-                                                    Ok(steps) => {
-                                                        Some(Some((Decision::SetForwardEdgeDistance(steps), socket)))
-                                                    },
-                                                    Err(e) => {
-                                                        socket.msg(&format!("Ignored: Error converting value: {}\n", e));
-                                                        Some(None)
-                                                    },
-                                                }
+                                    }
+                                }
+                            },
+                            _ => {
+                                socket.error("unrecognized", "Ignored: Unrecognized import type");
+                                None
+                            },
+                        }
+                    }
+                },
+                "jobs" => {
+                    if params.len() != 1 {
+                        socket.error("usage", "Usage: jobs");
+                        None
+                    } else {
+                        Some((Decision::Jobs, socket))
+                    }
+                },
+                "cancel" => {
+                    if params.len() != 2 {
+                        socket.error("usage", "Usage: cancel <job id>");
+                        None
+                    } else {
+                        match params[1].parse::<usize>() {
+                            Ok(id) => Some((Decision::Cancel(id), socket)),
+                            Err(e) => {
+                                socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                None
+                            },
+                        }
+                    }
+                },
+                "export" => {
+                    if params.len() < 2 {
+                        socket.error("usage", "Usage: export <export type>");
+                        socket.msg("Available export types: graph, messages");
+                        None
+                    } else {
+                        match &*params[1] {
+                            "graph" => {
+                                let usage = |s: &mut SocketLend| {
+                                    s.error("usage", "Usage: export graph <filename> [dot|graphml] [min <size>] \
+                                        [around <word> <hops>]");
+                                };
+                                if params.len() < 3 {
+                                    usage(&mut socket);
+                                    return None;
+                                }
+                                let mut format = GraphFormat::Dot;
+                                let mut min_size = 1;
+                                let mut around = None;
+                                let mut i = 3;
+                                while i < params.len() {
+                                    match &*params[i] {
+                                        "min" if i + 1 < params.len() => {
+                                            match params[i + 1].parse::<usize>() {
+                                                Ok(n) => min_size = n,
+                                                Err(e) => {
+                                                    socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                                    return None;
+                                                },
                                             }
+                                            i += 2;
                                         },
-                                        "bw_edge" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: set bw_edge <distance>");
-                                                Some(None)
-                                            } else {
-                                                match params[2].parse::<usize>() {
-                                                    Ok(steps) => {
-                                                        Some(Some((Decision::SetBackwardEdgeDistance(steps), socket)))
-                                                    },
-                                                    Err(e) => {
-                                                        socket.msg(&format!("Ignored: Error converting value: {}\n", e));
-                                                        Some(None)
-                                                    },
-                                                }
+                                        "around" if i + 2 < params.len() => {
+                                            match params[i + 2].parse::<usize>() {
+                                                Ok(hops) => around = Some((params[i + 1].clone(), hops)),
+                                                Err(e) => {
+                                                    socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                                    return None;
+                                                },
                                             }
+                                            i += 3;
                                         },
-                                        "fw_word" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: set fw_word <distance>");
-                                                Some(None)
-                                            } else {
-                                                match params[2].parse::<usize>() {
-                                                    Ok(steps) => {
-                                                        Some(Some((Decision::SetForwardWordDistance(steps), socket)))
-                                                    },
-                                                    Err(e) => {
-                                                        socket.msg(&format!("Ignored: Error converting value: {}\n", e));
-                                                        Some(None)
-                                                    },
-                                                }
+                                        s => {
+                                            match GraphFormat::parse(s) {
+                                                Some(f) => format = f,
+                                                None => {
+                                                    usage(&mut socket);
+                                                    return None;
+                                                },
                                             }
+                                            i += 1;
                                         },
-                                        "bw_word" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: set bw_word <distance>");
-                                                Some(None)
-                                            } else {
-                                                match params[2].parse::<usize>() {
-                                                    Ok(steps) => {
-                                                        Some(Some((Decision::SetBackwardWordDistance(steps), socket)))
-                                                    },
-                                                    Err(e) => {
-                                                        socket.msg(&format!("Ignored: Error converting value: {}\n", e));
-                                                        Some(None)
-                                                    },
-                                                }
+                                    }
+                                }
+                                Some((Decision::ExportGraph(params[2].to_string(), format, min_size, around), socket))
+                            },
+                            "messages" => {
+                                let usage = |s: &mut SocketLend| {
+                                    s.error("usage", "Usage: export messages <filename> [txt|jsonl] [source <name>] \
+                                        [author <name>] [since <date>] [until <date>]");
+                                };
+                                if params.len() < 3 {
+                                    usage(&mut socket);
+                                    return None;
+                                }
+                                let mut format = MessageFormat::Text;
+                                let mut filter = MessageFilter::default();
+                                let mut i = 3;
+                                while i < params.len() {
+                                    match (&*params[i], params.get(i + 1)) {
+                                        ("source", Some(name)) => filter.source = Some(name.clone()),
+                                        ("author", Some(name)) => filter.author = Some(name.clone()),
+                                        ("since", Some(date)) | ("until", Some(date)) => {
+                                            match import::parse_timestamp(date) {
+                                                Some(t) => if params[i] == "since" {
+                                                    filter.since = Some(t);
+                                                } else {
+                                                    filter.until = Some(t);
+                                                },
+                                                None => {
+                                                    socket.error("invalid_value", &format!("Ignored: Unable to parse date `{}`", date));
+                                                    return None;
+                                                },
                                             }
                                         },
-                                        _ => {
-                                            socket.msg("Ignored: Unrecognized set value");
-                                            Some(None)
+                                        (s, _) => {
+                                            match MessageFormat::parse(s) {
+                                                Some(f) => format = f,
+                                                None => {
+                                                    usage(&mut socket);
+                                                    return None;
+                                                },
+                                            }
+                                            i += 1;
+                                            continue;
                                         },
                                     }
+                                    i += 2;
                                 }
+                                Some((Decision::ExportMessages(params[2].to_string(), format, filter), socket))
+                            },
+                            _ => {
+                                socket.error("unrecognized", "Ignored: Unrecognized export type");
+                                None
                             },
-                            "get" => {
-                                if params.len() < 2 {
-                                    socket.msg("Usage: get <value>");
-                                    socket.msg("Values: cc_ratio, cc_travel, cc_mag, fw_edge, bw_edge, fw_word, bw_word");
-                                    Some(None)
+                        }
+                    }
+                },
+                "set" => {
+                    if params.len() < 2 {
+                        socket.error("usage", "Usage: set <value>");
+                        socket.msg("Values: cc_ratio, cc_travel, cc_mag, fw_edge, bw_edge, fw_word, bw_word");
+                        None
+                    } else {
+                        match &*params[1] {
+                            "cc_ratio" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: set cc_ratio <ratio>");
+                                    None
                                 } else {
-                                    match &*params[1] {
-                                        "cc_ratio" => {
-                                            if params.len() != 2 {
-                                                socket.msg("Usage: get cc_ratio");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::GetCocategoryRatio, socket)))
-                                            }
+                                    match params[2].parse::<f64>() {
+                                        Ok(f) => {
+                                            Some((Decision::SetCocategoryRatio(f), socket))
                                         },
-                                        "cc_travel" => {
-                                            if params.len() != 2 {
-                                                socket.msg("Usage: get cc_travel");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::GetTravelDistance, socket)))
-                                            }
+                                        Err(e) => {
+                                            socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                            None
                                         },
-                                        "cc_mag" => {
-                                            if params.len() != 2 {
-                                                socket.msg("Usage: get cc_mag");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::GetCocategorizeMagnitude, socket)))
-                                            }
+                                    }
+                                }
+                            },
+                            "cc_travel" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: set cc_travel <steps>");
+                                    None
+                                } else {
+                                    match params[2].parse::<i32>() {
+                                        Ok(steps) => {
+                                            Some((Decision::SetTravelDistance(steps), socket))
                                         },
-                                        "fw_edge" => {
-                                            if params.len() != 2 {
-                                                socket.msg("Usage: get fw_edge");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::GetForwardEdgeDistance, socket)))
-                                            }
+                                        Err(e) => {
+                                            socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                            None
                                         },
-                                        "bw_edge" => {
-                                            if params.len() != 2 {
-                                                socket.msg("Usage: get bw_edge");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::GetBackwardEdgeDistance, socket)))
-                                            }
+                                    }
+                                }
+                            },
+                            "cc_mag" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: set cc_mag <cycles>");
+                                    None
+                                } else {
+                                    match params[2].parse::<i32>() {
+                                        Ok(steps) => {
+                                            Some((Decision::SetCocategorizeMagnitude(steps), socket))
                                         },
-                                        "fw_word" => {
-                                            if params.len() != 2 {
-                                                socket.msg("Usage: get fw_word");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::GetForwardWordDistance, socket)))
-                                            }
+                                        Err(e) => {
+                                            socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                            None
                                         },
-                                        "bw_word" => {
-                                            if params.len() != 2 {
-                                                socket.msg("Usage: get bw_word");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::GetBackwardWordDistance, socket)))
-                                            }
+                                    }
+                                }
+                            },
+                            "fw_edge" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: set fw_edge <distance>");
+                                    None
+                                } else {
+                                    match params[2].parse::<usize>() {
+                                        Ok(steps) => {
+                                            Some((Decision::SetForwardEdgeDistance(steps), socket))
                                         },
-                                        _ => {
-                                            socket.msg("Ignored: Unrecognized get value");
-                                            Some(None)
+                                        Err(e) => {
+                                            socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                            None
                                         },
                                     }
                                 }
                             },
-                            "connect" => {
-                                if params.len() < 2 {
-                                    socket.msg("Ignored: connect takes at least a connect type");
-                                    socket.msg("Connect types: server, irc, discord");
-                                    Some(None)
+                            "bw_edge" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: set bw_edge <distance>");
+                                    None
                                 } else {
-                                    match &*params[1] {
-                                        "server" => {
-                                            if params.len() != 2 {
-                                                socket.msg("Ignored: no extra parameters needed");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::ConnectServer, socket)))
-                                            }
+                                    match params[2].parse::<usize>() {
+                                        Ok(steps) => {
+                                            Some((Decision::SetBackwardEdgeDistance(steps), socket))
                                         },
-                                        "irc" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: connect irc <config>");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::ConnectIrc(params[2].to_string()), socket)))
-                                            }
+                                        Err(e) => {
+                                            socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                            None
                                         },
-                                        "discord" => {
-                                            if params.len() != 3 {
-                                                socket.msg("Usage: connect discord <config>");
-                                                Some(None)
-                                            } else {
-                                                Some(Some((Decision::ConnectDiscord(params[2].to_string()), socket)))
-                                            }
+                                    }
+                                }
+                            },
+                            "fw_word" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: set fw_word <distance>");
+                                    None
+                                } else {
+                                    match params[2].parse::<usize>() {
+                                        Ok(steps) => {
+                                            Some((Decision::SetForwardWordDistance(steps), socket))
                                         },
-                                        _ => {
-                                            socket.msg("Ignored: Unrecognized connect type");
-                                            Some(None)
+                                        Err(e) => {
+                                            socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                            None
                                         },
                                     }
                                 }
                             },
-                            "list" => {
-                                if params.len() != 2 {
-                                    socket.msg("Usage: list <list type>");
-                                    socket.msg("Available list types: categories");
-                                    Some(None)
+                            "bw_word" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: set bw_word <distance>");
+                                    None
                                 } else {
-                                    match &*params[1] {
-                                        "categories" => {
-                                            Some(Some((Decision::ShowCategories, socket)))
+                                    match params[2].parse::<usize>() {
+                                        Ok(steps) => {
+                                            Some((Decision::SetBackwardWordDistance(steps), socket))
                                         },
-                                        _ => {
-                                            socket.msg("Ignored: Unrecognized list type");
-                                            Some(None)
+                                        Err(e) => {
+                                            socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                            None
                                         },
                                     }
                                 }
                             },
-                            "respond" => {
-                                if params.len() != 1 {
-                                    socket.msg("Usage: respond");
-                                    Some(None)
+                            _ => {
+                                socket.error("unrecognized", "Ignored: Unrecognized set value");
+                                None
+                            },
+                        }
+                    }
+                },
+                "get" => {
+                    if params.len() < 2 {
+                        socket.error("usage", "Usage: get <value>");
+                        socket.msg("Values: all, cc_ratio, cc_travel, cc_mag, fw_edge, bw_edge, fw_word, bw_word");
+                        None
+                    } else {
+                        match &*params[1] {
+                            "all" => {
+                                if params.len() != 2 {
+                                    socket.error("usage", "Usage: get all");
+                                    None
                                 } else {
-                                    Some(Some((Decision::Respond, socket)))
+                                    Some((Decision::GetAll, socket))
                                 }
                             },
-                            "tell" => {
+                            "cc_ratio" => {
                                 if params.len() != 2 {
-                                    socket.msg("Usage: tell <message>");
-                                    Some(None)
+                                    socket.error("usage", "Usage: get cc_ratio");
+                                    None
                                 } else {
-                                    Some(Some((Decision::Tell(params[1].to_string()), socket)))
+                                    Some((Decision::GetCocategoryRatio, socket))
                                 }
                             },
-                            "find" => {
-                                if params.len() < 2 {
-                                    socket.msg("Usage: find <type>");
-                                    socket.msg("Types: relation");
-                                    Some(None)
+                            "cc_travel" => {
+                                if params.len() != 2 {
+                                    socket.error("usage", "Usage: get cc_travel");
+                                    None
                                 } else {
-                                    match &*params[1] {
-                                        "relation" => {
-                                            if params.len() == 4 {
-                                                Some(Some((
-                                                    Decision::FindRelation((params[2].clone(), params[3].clone())),
-                                                    socket,
-                                                )))
-                                            } else {
-                                                socket.msg("Usage: find relation <word1> <word2>");
-                                                Some(None)
-                                            }
-                                        },
-                                        _ => {
-                                            socket.msg("Ignored: Unrecognized find type");
-                                            Some(None)
-                                        }
-                                    }
+                                    Some((Decision::GetTravelDistance, socket))
+                                }
+                            },
+                            "cc_mag" => {
+                                if params.len() != 2 {
+                                    socket.error("usage", "Usage: get cc_mag");
+                                    None
+                                } else {
+                                    Some((Decision::GetCocategorizeMagnitude, socket))
+                                }
+                            },
+                            "fw_edge" => {
+                                if params.len() != 2 {
+                                    socket.error("usage", "Usage: get fw_edge");
+                                    None
+                                } else {
+                                    Some((Decision::GetForwardEdgeDistance, socket))
+                                }
+                            },
+                            "bw_edge" => {
+                                if params.len() != 2 {
+                                    socket.error("usage", "Usage: get bw_edge");
+                                    None
+                                } else {
+                                    Some((Decision::GetBackwardEdgeDistance, socket))
+                                }
+                            },
+                            "fw_word" => {
+                                if params.len() != 2 {
+                                    socket.error("usage", "Usage: get fw_word");
+                                    None
+                                } else {
+                                    Some((Decision::GetForwardWordDistance, socket))
+                                }
+                            },
+                            "bw_word" => {
+                                if params.len() != 2 {
+                                    socket.error("usage", "Usage: get bw_word");
+                                    None
+                                } else {
+                                    Some((Decision::GetBackwardWordDistance, socket))
                                 }
                             },
                             _ => {
-                                socket.msg("Ignored: Unrecognized command");
-                                Some(None)
+                                socket.error("unrecognized", "Ignored: Unrecognized get value");
+                                None
                             },
                         }
                     }
-                }
-            },
-            Err(TryRecvError::Empty) => {
-                Some(None)
-            },
-            Err(TryRecvError::Disconnected) => {
-                None
-            },
+                },
+                "connect" => {
+                    if params.len() < 2 {
+                        socket.error("usage", "Ignored: connect takes at least a connect type");
+                        socket.msg("Connect types: server, http, websocket, irc, discord");
+                        None
+                    } else {
+                        match &*params[1] {
+                            "server" => {
+                                match params.len() {
+                                    2 => Some((Decision::ConnectServer(None), socket)),
+                                    3 => Some((Decision::ConnectServer(Some(params[2].to_string())), socket)),
+                                    _ => {
+                                        socket.error("usage", "Usage: connect server [address:port | unix:path]");
+                                        None
+                                    },
+                                }
+                            },
+                            "http" => {
+                                match params.len() {
+                                    2 => Some((Decision::ConnectHttp(None), socket)),
+                                    3 => Some((Decision::ConnectHttp(Some(params[2].to_string())), socket)),
+                                    _ => {
+                                        socket.error("usage", "Usage: connect http [address:port]");
+                                        None
+                                    },
+                                }
+                            },
+                            "websocket" => {
+                                match params.len() {
+                                    2 => Some((Decision::ConnectWebSocket(None), socket)),
+                                    3 => Some((Decision::ConnectWebSocket(Some(params[2].to_string())), socket)),
+                                    _ => {
+                                        socket.error("usage", "Usage: connect websocket [address:port]");
+                                        None
+                                    },
+                                }
+                            },
+                            "irc" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: connect irc <config>");
+                                    None
+                                } else {
+                                    Some((Decision::ConnectIrc(params[2].to_string()), socket))
+                                }
+                            },
+                            "discord" => {
+                                if params.len() != 3 {
+                                    socket.error("usage", "Usage: connect discord <config>");
+                                    None
+                                } else {
+                                    Some((Decision::ConnectDiscord(params[2].to_string()), socket))
+                                }
+                            },
+                            _ => {
+                                socket.error("unrecognized", "Ignored: Unrecognized connect type");
+                                None
+                            },
+                        }
+                    }
+                },
+                "list" => {
+                    if params.len() != 2 {
+                        socket.error("usage", "Usage: list <list type>");
+                        socket.msg("Available list types: categories");
+                        None
+                    } else {
+                        match &*params[1] {
+                            "categories" => {
+                                Some((Decision::ShowCategories, socket))
+                            },
+                            _ => {
+                                socket.error("unrecognized", "Ignored: Unrecognized list type");
+                                None
+                            },
+                        }
+                    }
+                },
+                "preset" => {
+                    match (params.get(1).map(|s| s.as_str()), params.len()) {
+                        (Some("save"), 3) => Some((Decision::PresetSave(params[2].to_string()), socket)),
+                        (Some("load"), 3) => Some((Decision::PresetLoad(params[2].to_string()), socket)),
+                        (Some("list"), 2) => Some((Decision::PresetList, socket)),
+                        _ => {
+                            socket.error("usage", "Usage: preset save <name> | preset load <name> | preset list");
+                            None
+                        },
+                    }
+                },
+                "connections" => {
+                    if params.len() != 1 {
+                        socket.error("usage", "Usage: connections");
+                        None
+                    } else {
+                        Some((Decision::Connections, socket))
+                    }
+                },
+                "disconnect" | "reconnect" => {
+                    let id = if params.len() == 2 { params[1].parse::<usize>().ok() } else { None };
+                    match (id, &*params[0]) {
+                        (Some(id), "disconnect") => Some((Decision::Disconnect(id), socket)),
+                        (Some(id), _) => Some((Decision::Reconnect(id), socket)),
+                        (None, command) => {
+                            socket.error("usage", &format!("Usage: {} <connection id>", command));
+                            None
+                        },
+                    }
+                },
+                "reload" => {
+                    if params.len() != 1 {
+                        socket.error("usage", "Usage: reload");
+                        None
+                    } else {
+                        Some((Decision::Reload, socket))
+                    }
+                },
+                "save" => {
+                    match params.len() {
+                        1 => Some((Decision::Save(None), socket)),
+                        2 => Some((Decision::Save(Some(params[1].to_string())), socket)),
+                        _ => {
+                            socket.error("usage", "Usage: save [filename]");
+                            None
+                        },
+                    }
+                },
+                "stats" => {
+                    if params.len() != 1 {
+                        socket.error("usage", "Usage: stats");
+                        None
+                    } else {
+                        Some((Decision::Stats, socket))
+                    }
+                },
+                "respond" => {
+                    if params.len() != 1 {
+                        socket.error("usage", "Usage: respond");
+                        None
+                    } else {
+                        Some((Decision::Respond, socket))
+                    }
+                },
+                "forget" => {
+                    let remove = params.get(2).map_or(false, |p| p == "remove");
+                    let rest = if remove { 3 } else { 2 };
+                    if params.get(1).map_or(true, |p| p != "last") || params.len() > rest + 1 {
+                        socket.error("usage", "Usage: forget last [remove] [<source>]");
+                        None
+                    } else {
+                        Some((Decision::ForgetLast(params.get(rest).cloned(), remove), socket))
+                    }
+                },
+                "tell" => {
+                    if params.len() != 2 {
+                        socket.error("usage", "Usage: tell <message>");
+                        None
+                    } else {
+                        Some((Decision::Tell(params[1].to_string()), socket))
+                    }
+                },
+                "find" => {
+                    if params.len() < 2 {
+                        socket.error("usage", "Usage: find <type>");
+                        socket.msg("Types: relation, word");
+                        None
+                    } else {
+                        match &*params[1] {
+                            "relation" => {
+                                if params.len() == 4 {
+                                    Some((
+                                        Decision::FindRelation((params[2].clone(), params[3].clone())),
+                                        socket,
+                                    ))
+                                } else {
+                                    socket.error("usage", "Usage: find relation <word1> <word2>");
+                                    None
+                                }
+                            },
+                            "word" => {
+                                match params.len() {
+                                    3 => Some((Decision::FindWord((params[2].clone(), 1)), socket)),
+                                    4 => {
+                                        match params[3].parse::<usize>() {
+                                            Ok(hops) => {
+                                                Some((Decision::FindWord((params[2].clone(), hops)), socket))
+                                            },
+                                            Err(e) => {
+                                                socket.error("invalid_value", &format!("Ignored: Error converting value: {}\n", e));
+                                                None
+                                            },
+                                        }
+                                    },
+                                    _ => {
+                                        socket.error("usage", "Usage: find word <word> [hops]");
+                                        None
+                                    },
+                                }
+                            },
+                            _ => {
+                                socket.error("unrecognized", "Ignored: Unrecognized find type");
+                                None
+                            }
+                        }
+                    }
+                },
+                _ => {
+                    socket.error("unrecognized", "Ignored: Unrecognized command");
+                    None
+                },
+            }
         }
     }
 }
@@ -0,0 +1,419 @@
+extern crate rand;
+extern crate regex;
+extern crate serde_json;
+
+use self::regex::Regex;
+use text::Lexicon;
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::str::FromStr;
+
+/// Config file read when none is given on the command line, if it exists
+pub const DEFAULT_PATH: &'static str = "seifmios.json";
+/// Where named presets are kept unless configured otherwise
+pub const PRESETS_PATH: &'static str = "seifmios_presets.json";
+
+/// Everything that can be set when seifmios starts. Anything left out keeps its usual default.
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    /// Seed for the lexicon's random number generator
+    pub seed: Option<Vec<u64>>,
+    /// Name of the source that cli commands like `tell` and `respond` use
+    pub console: Option<String>,
+    /// zmq endpoint the cli listens on
+    pub endpoint: Option<String>,
+    pub admin_token: Option<String>,
+    pub read_token: Option<String>,
+    /// Lexicon snapshot loaded at startup and written by `save`
+    pub snapshot: Option<String>,
+    /// Where the offsets of unfinished imports are kept
+    pub import_offsets: Option<String>,
+    /// Where named presets are kept
+    pub presets: Option<String>,
+    /// Preset applied at startup, before the lexicon values below
+    pub preset: Option<String>,
+    #[serde(default)]
+    pub lexicon: LexiconConfig,
+    /// Chat adapters to connect at startup
+    #[serde(default)]
+    pub connections: Vec<Connection>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct LexiconConfig {
+    pub cocategorization_ratio: Option<f64>,
+    pub cocategory_travel_distance: Option<i32>,
+    pub cocategorize_magnitude: Option<i32>,
+    pub forward_edge_distance: Option<usize>,
+    pub backward_edge_distance: Option<usize>,
+    pub forward_word_distance: Option<usize>,
+    pub backward_word_distance: Option<usize>,
+}
+
+impl LexiconConfig {
+    /// Get every value the lexicon currently has
+    pub fn current<R: rand::Rng>(lex: &Lexicon<R>) -> LexiconConfig {
+        LexiconConfig{
+            cocategorization_ratio: Some(lex.cocategorization_ratio),
+            cocategory_travel_distance: Some(lex.cocategory_travel_distance),
+            cocategorize_magnitude: Some(lex.cocategorize_magnitude),
+            forward_edge_distance: Some(lex.forward_edge_distance),
+            backward_edge_distance: Some(lex.backward_edge_distance),
+            forward_word_distance: Some(lex.forward_word_distance),
+            backward_word_distance: Some(lex.backward_word_distance),
+        }
+    }
+
+    /// Set every value that was given on the lexicon
+    pub fn apply<R: rand::Rng>(&self, lex: &mut Lexicon<R>) {
+        if let Some(v) = self.cocategorization_ratio {
+            lex.cocategorization_ratio = v;
+        }
+        if let Some(v) = self.cocategory_travel_distance {
+            lex.cocategory_travel_distance = v;
+        }
+        if let Some(v) = self.cocategorize_magnitude {
+            lex.cocategorize_magnitude = v;
+        }
+        if let Some(v) = self.forward_edge_distance {
+            lex.forward_edge_distance = v;
+        }
+        if let Some(v) = self.backward_edge_distance {
+            lex.backward_edge_distance = v;
+        }
+        if let Some(v) = self.forward_word_distance {
+            lex.forward_word_distance = v;
+        }
+        if let Some(v) = self.backward_word_distance {
+            lex.backward_word_distance = v;
+        }
+    }
+
+    /// Set a value from an assignment like `cocategorization_ratio=0.5`, which is how the command line overrides
+    /// the file.
+    pub fn set(&mut self, assignment: &str) -> Result<(), String> {
+        let (key, value) = match assignment.find('=') {
+            Some(i) => (&assignment[..i], &assignment[i + 1..]),
+            None => return Err(format!("Expected <name>=<value> but got `{}`", assignment)),
+        };
+        match key {
+            "cocategorization_ratio" => self.cocategorization_ratio = try!(parse(key, value)),
+            "cocategory_travel_distance" => self.cocategory_travel_distance = try!(parse(key, value)),
+            "cocategorize_magnitude" => self.cocategorize_magnitude = try!(parse(key, value)),
+            "forward_edge_distance" => self.forward_edge_distance = try!(parse(key, value)),
+            "backward_edge_distance" => self.backward_edge_distance = try!(parse(key, value)),
+            "forward_word_distance" => self.forward_word_distance = try!(parse(key, value)),
+            "backward_word_distance" => self.backward_word_distance = try!(parse(key, value)),
+            _ => return Err(format!("Unrecognized lexicon value `{}`", key)),
+        }
+        Ok(())
+    }
+}
+
+/// Name of the policy used for channels that don't have their own
+pub const ANY_CHANNEL: &'static str = "*";
+
+/// A chat adapter, like `{"type": "irc", "config": "irc.json"}` or `{"type": "server", "address": "[::1]:2933"}`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Connection {
+    /// One of `server`, `http`, `websocket`, `irc` or `discord`
+    #[serde(rename="type")]
+    pub kind: String,
+    /// Config file of the adapter; the server doesn't have one
+    pub config: Option<String>,
+    /// Address the server, HTTP API or WebSocket gateway listens on
+    pub address: Option<String>,
+    /// How to reply in each channel, by source name, with `"*"` for every other channel
+    #[serde(default)]
+    pub channels: BTreeMap<String, ReplyPolicy>,
+    /// Messages to drop before they reach the lexicon
+    #[serde(default)]
+    pub ignore: IgnoreRules,
+    /// Patterns of who can give owner commands in chat, matched against an IRC hostmask or a Discord user ID
+    #[serde(default)]
+    pub owners: Vec<String>,
+    /// What replies have to pass before they are sent
+    #[serde(default)]
+    pub filter: FilterConfig,
+}
+
+impl Connection {
+    pub fn new(kind: &str, config: Option<String>, address: Option<String>) -> Connection {
+        Connection{
+            kind: kind.to_string(),
+            config: config,
+            address: address,
+            channels: BTreeMap::new(),
+            ignore: IgnoreRules::default(),
+            owners: Vec::new(),
+            filter: FilterConfig::default(),
+        }
+    }
+
+    /// Make sure every channel's policy and every pattern makes sense before anything is started.
+    pub fn check(&self) -> Result<(), String> {
+        for (channel, policy) in &self.channels {
+            try!(policy.check().map_err(|e| format!("Invalid policy for channel `{}`: {}", channel, e)));
+        }
+        try!(self.ignore.authors().map_err(|e| format!("Invalid ignored author: {}", e)));
+        try!(patterns(&self.owners).map_err(|e| format!("Invalid owner: {}", e)));
+        try!(self.filter.check().map_err(|e| format!("Invalid filter: {}", e)));
+        Ok(())
+    }
+
+    /// The policy of a channel, filled in from the `"*"` policy
+    pub fn policy(&self, channel: &str) -> ReplyPolicy {
+        let any = self.channels.get(ANY_CHANNEL).cloned().unwrap_or_default();
+        match self.channels.get(channel) {
+            Some(policy) => policy.or(&any),
+            None => any,
+        }
+    }
+}
+
+/// When the bot replies in a channel, like `{"reply": "always", "probability": 0.2, "quiet_hours": [23, 7]}`.
+/// Being mentioned or asked directly always counts, unless the bot never replies or it is quiet hours.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ReplyPolicy {
+    /// `mention` to reply only when mentioned (the default), `always` to reply to anything, or `never` to only
+    /// learn
+    pub reply: Option<String>,
+    /// Chance of replying to something that didn't mention the bot, which is 1 when replying to anything and 0
+    /// when replying to mentions unless given
+    pub probability: Option<f64>,
+    /// Hours, in UTC, from which and until which the bot says nothing, like `[23, 7]`
+    pub quiet_hours: Option<(u32, u32)>,
+    /// Least seconds between replies. Without one only replies to mentions are limited, to one every 1.5 seconds.
+    pub cooldown: Option<f64>,
+    /// Seconds of silence after which the bot says something by itself, once, until someone speaks again
+    pub initiate_after: Option<u64>,
+    /// False to reply without learning anything said in the channel
+    pub learn: Option<bool>,
+    /// True to act as if the channel doesn't exist
+    pub ignore: Option<bool>,
+}
+
+impl ReplyPolicy {
+    fn check(&self) -> Result<(), String> {
+        match self.reply.as_ref().map(|r| r.as_str()) {
+            None | Some("mention") | Some("always") | Some("never") => {},
+            Some(r) => return Err(format!("Unrecognized reply `{}`, expected mention, always or never", r)),
+        }
+        if let Some(p) = self.probability {
+            if p < 0.0 || p > 1.0 {
+                return Err(format!("Probability {} isn't between 0 and 1", p));
+            }
+            if self.reply.as_ref().map_or(false, |r| r.as_str() == "never") {
+                return Err("A probability can't be given when the bot never replies".to_string());
+            }
+        }
+        if let Some((from, until)) = self.quiet_hours {
+            if from > 23 || until > 23 {
+                return Err(format!("Quiet hours {} to {} aren't hours of the day", from, until));
+            }
+        }
+        if let Some(c) = self.cooldown {
+            if c < 0.0 {
+                return Err(format!("Cooldown {} is negative", c));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill in anything left out from another policy.
+    fn or(&self, other: &ReplyPolicy) -> ReplyPolicy {
+        ReplyPolicy{
+            reply: self.reply.clone().or_else(|| other.reply.clone()),
+            probability: self.probability.or(other.probability),
+            quiet_hours: self.quiet_hours.or(other.quiet_hours),
+            cooldown: self.cooldown.or(other.cooldown),
+            initiate_after: self.initiate_after.or(other.initiate_after),
+            learn: self.learn.or(other.learn),
+            ignore: self.ignore.or(other.ignore),
+        }
+    }
+}
+
+/// Messages that are neither learned from nor replied to, like `{"authors": [".*bot", "spam!.*@.*"],
+/// "prefixes": ["!"]}`
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct IgnoreRules {
+    /// Patterns that have to match an author's whole name, which on IRC includes the hostmask
+    #[serde(default)]
+    pub authors: Vec<String>,
+    /// Messages starting with any of these, like commands meant for other bots
+    #[serde(default)]
+    pub prefixes: Vec<String>,
+}
+
+impl IgnoreRules {
+    /// Compile the author patterns.
+    pub fn authors(&self) -> Result<Vec<Regex>, String> {
+        patterns(&self.authors)
+    }
+}
+
+/// What replies have to pass before they are sent, like `{"words": ["heck"], "patterns": ["(?i)free \\w+"],
+/// "urls": "strip", "max_length": 300}`. Replies that don't pass are made again.
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct FilterConfig {
+    /// Words that are never said, whatever their case
+    #[serde(default)]
+    pub words: Vec<String>,
+    /// Patterns that can't match anywhere in a reply
+    #[serde(default)]
+    pub patterns: Vec<String>,
+    /// False to leave pings like `@everyone` and `<@1234>` in replies
+    pub strip_mentions: Option<bool>,
+    /// `allow` links (the default), `strip` them out, or `reject` any reply with one
+    pub urls: Option<String>,
+    /// Most characters in a reply, 400 unless given
+    pub max_length: Option<usize>,
+}
+
+impl FilterConfig {
+    fn check(&self) -> Result<(), String> {
+        try!(self.patterns());
+        match self.urls.as_ref().map(|u| u.as_str()) {
+            None | Some("allow") | Some("strip") | Some("reject") => Ok(()),
+            Some(u) => Err(format!("Unrecognized urls `{}`, expected allow, strip or reject", u)),
+        }
+    }
+
+    /// Compile the patterns.
+    pub fn patterns(&self) -> Result<Vec<Regex>, String> {
+        self.patterns.iter().map(|p| Regex::new(p).map_err(|e| format!("`{}`: {}", p, e))).collect()
+    }
+}
+
+/// Compile patterns that each have to match a whole name.
+pub fn patterns(patterns: &[String]) -> Result<Vec<Regex>, String> {
+    patterns.iter()
+        .map(|p| Regex::new(&format!("^(?:{})$", p)).map_err(|e| format!("`{}`: {}", p, e)))
+        .collect()
+}
+
+impl Config {
+    /// Load the given config, or the default one if it exists, or use the defaults for everything.
+    pub fn find(path: Option<&str>) -> Result<Config, String> {
+        match path {
+            Some(path) => Config::load(path),
+            None if Path::new(DEFAULT_PATH).exists() => Config::load(DEFAULT_PATH),
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+        let file = try!(File::open(path).map_err(|e| format!("Unable to open config: {}", e)));
+        serde_json::from_reader(file).map_err(|e| format!("Unable to read config: {}", e))
+    }
+}
+
+/// Named sets of lexicon values, saved to a file whenever one is added
+pub struct Presets {
+    path: String,
+    presets: BTreeMap<String, LexiconConfig>,
+}
+
+impl Presets {
+    /// Load the presets, starting with none if the file doesn't exist yet.
+    pub fn load(path: String) -> Result<Presets, String> {
+        let presets = match File::open(&path) {
+            Ok(file) => try!(serde_json::from_reader(file).map_err(|e| format!("Unable to read presets: {}", e))),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(format!("Unable to open presets: {}", e)),
+        };
+        Ok(Presets{
+            path: path,
+            presets: presets,
+        })
+    }
+
+    pub fn get(&self, name: &str) -> Option<&LexiconConfig> {
+        self.presets.get(name)
+    }
+
+    pub fn names(&self) -> Vec<&String> {
+        self.presets.keys().collect()
+    }
+
+    /// Add or replace a preset and write them all out.
+    pub fn insert(&mut self, name: String, preset: LexiconConfig) -> Result<(), String> {
+        self.presets.insert(name, preset);
+        let mut file = try!(File::create(&self.path).map_err(|e| format!("Unable to create presets: {}", e)));
+        serde_json::to_writer_pretty(&mut file, &self.presets).map_err(|e| format!("Unable to write presets: {}", e))
+    }
+}
+
+fn parse<T>(key: &str, value: &str) -> Result<Option<T>, String>
+    where T: FromStr, T::Err: Display
+{
+    value.parse().map(Some).map_err(|e| format!("Invalid value for {}: {}", key, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ANY_CHANNEL, Connection, ReplyPolicy};
+
+    fn policy(reply: &str) -> ReplyPolicy {
+        ReplyPolicy{
+            reply: Some(reply.to_string()),
+            ..ReplyPolicy::default()
+        }
+    }
+
+    #[test]
+    fn check_policies() {
+        assert!(ReplyPolicy::default().check().is_ok());
+        assert!(policy("always").check().is_ok());
+        assert!(policy("sometimes").check().is_err());
+        assert!(ReplyPolicy{probability: Some(1.5), ..ReplyPolicy::default()}.check().is_err());
+        assert!(ReplyPolicy{probability: Some(0.0), ..ReplyPolicy::default()}.check().is_ok());
+        assert!(ReplyPolicy{probability: Some(0.5), ..policy("never")}.check().is_err());
+        assert!(ReplyPolicy{quiet_hours: Some((23, 7)), ..ReplyPolicy::default()}.check().is_ok());
+        assert!(ReplyPolicy{quiet_hours: Some((22, 24)), ..ReplyPolicy::default()}.check().is_err());
+        assert!(ReplyPolicy{cooldown: Some(-1.0), ..ReplyPolicy::default()}.check().is_err());
+    }
+
+    #[test]
+    fn or_fills_in_what_is_missing() {
+        let channel = ReplyPolicy{
+            reply: Some("always".to_string()),
+            learn: Some(false),
+            ..ReplyPolicy::default()
+        };
+        let any = ReplyPolicy{
+            reply: Some("never".to_string()),
+            cooldown: Some(2.0),
+            learn: Some(true),
+            ..ReplyPolicy::default()
+        };
+        assert_eq!(channel.or(&any), ReplyPolicy{
+            reply: Some("always".to_string()),
+            cooldown: Some(2.0),
+            learn: Some(false),
+            ..ReplyPolicy::default()
+        });
+    }
+
+    #[test]
+    fn channel_policies() {
+        let mut connection = Connection::new("irc", None, None);
+        connection.channels.insert(ANY_CHANNEL.to_string(), ReplyPolicy{
+            reply: Some("never".to_string()),
+            cooldown: Some(2.0),
+            ..ReplyPolicy::default()
+        });
+        connection.channels.insert("#chat".to_string(), policy("always"));
+        assert_eq!(connection.policy("#chat").reply, Some("always".to_string()));
+        assert_eq!(connection.policy("#chat").cooldown, Some(2.0));
+        assert_eq!(connection.policy("#other").reply, Some("never".to_string()));
+        assert!(connection.check().is_ok());
+        connection.channels.insert("#bad".to_string(), policy("loudly"));
+        assert!(connection.check().is_err());
+    }
+}
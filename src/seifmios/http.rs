@@ -0,0 +1,353 @@
+extern crate tiny_http;
+extern crate serde;
+extern crate serde_json;
+
+use self::tiny_http::{Header, Method, Request, Response, Server};
+use self::serde_json::Value;
+use chat::Stop;
+use cli::{Commands, Decision, Reply, STATUS_OK};
+use cli::auth::{Auth, Permission};
+use config::LexiconConfig;
+
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::Ordering;
+
+/// Address the API listens on when none is given
+pub const DEFAULT_ADDRESS: &'static str = "127.0.0.1:2935";
+
+/// Author of messages told without one
+const DEFAULT_AUTHOR: &'static str = "http";
+
+#[derive(Deserialize, Debug)]
+struct TellBody {
+    source: String,
+    author: Option<String>,
+    message: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct RespondBody {
+    source: String,
+    author: Option<String>,
+    /// Told to the source before responding
+    prompt: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SaveBody {
+    path: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ReplyBody {
+    reply: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct MessagesBody {
+    messages: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+struct ErrorBody {
+    /// Same codes as the cli uses, like `usage` or `not_found`
+    error: String,
+    message: String,
+}
+
+/// Bind the API's address. This is done before starting its thread so a failure can be reported.
+pub fn bind(address: &str) -> Result<Server, String> {
+    Server::http(address).map_err(|e| format!("Unable to bind `{}`: {}", address, e))
+}
+
+/// Answer requests until stopped. The endpoints are:
+///
+/// - `POST /tell` with `{"source": "s", "author": "a", "message": "m"}`
+/// - `POST /respond` with `{"source": "s", "author": "a", "prompt": "p"}`, where the prompt is optional but needs
+///   the same permission as `/tell`
+/// - `GET /stats`
+/// - `GET /params` and `PUT /params` with any of the lexicon values from the config
+/// - `POST /save` with `{"path": "snapshot.json"}`, where the path is optional
+///
+/// Tokens are given as `Authorization: Bearer <token>` and allow the same things as they do for the cli.
+pub fn serve(server: Server, commands: Commands, auth: Auth, stop: Stop) {
+    let json = "Content-Type: application/json".parse::<Header>().unwrap();
+    for mut request in server.incoming_requests() {
+        if stop.load(Ordering::SeqCst) {
+            break;
+        }
+        let (status, body) = match handle(&mut request, &commands, &auth) {
+            Ok(body) => (200, body),
+            Err((status, code, message)) => {
+                let body = ErrorBody{
+                    error: code,
+                    message: message,
+                };
+                (status, serde_json::to_string(&body).unwrap_or_else(|_| String::new()))
+            },
+        };
+        let response = Response::from_string(body).with_status_code(status).with_header(json.clone());
+        request.respond(response).unwrap_or_else(|e| {
+            println!("Warning: Unable to send HTTP response: {}", e);
+        });
+    }
+}
+
+/// Send a request to the API so it notices it has been stopped without waiting for a client.
+pub fn wake(address: &str) {
+    if let Ok(mut stream) = TcpStream::connect(address) {
+        let _ = stream.write_all(b"GET / HTTP/1.0\r\n\r\n");
+    }
+}
+
+/// HTTP status, error code and message
+type Failure = (u16, String, String);
+
+fn fail(status: u16, code: &str, message: String) -> Failure {
+    (status, code.to_string(), message)
+}
+
+fn handle(request: &mut Request, commands: &Commands, auth: &Auth) -> Result<String, Failure> {
+    let permission = match token(request) {
+        Some(token) => {
+            try!(auth.check(&token).ok_or_else(|| fail(401, "unauthorized", "Invalid token".to_string())))
+        },
+        None => auth.unauthenticated(),
+    };
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    let mut body = String::new();
+    try!(request.as_reader().read_to_string(&mut body)
+        .map_err(|e| fail(400, "io", format!("Unable to read request: {}", e))));
+
+    // Each endpoint needs the same permission as the cli command it stands for
+    let (command, decision) = match (request.method(), path.as_str()) {
+        (&Method::Post, "/tell") => {
+            let b: TellBody = try!(parse(&body));
+            ("tell", Decision::TellTo(b.source, b.author.unwrap_or_else(|| DEFAULT_AUTHOR.to_string()), b.message))
+        },
+        (&Method::Post, "/respond") => {
+            let b: RespondBody = try!(parse(&body));
+            let author = b.author.unwrap_or_else(|| DEFAULT_AUTHOR.to_string());
+            ("respond", Decision::RespondTo(b.source, b.prompt.map(|p| (author, p))))
+        },
+        (&Method::Get, "/stats") => ("stats", Decision::Stats),
+        (&Method::Get, "/params") => ("get", Decision::GetParams),
+        (&Method::Put, "/params") => ("set", Decision::SetParams(try!(parse::<LexiconConfig>(&body)))),
+        (&Method::Post, "/save") => {
+            let b: SaveBody = if body.trim().is_empty() { SaveBody{path: None} } else { try!(parse(&body)) };
+            ("save", Decision::Save(b.path))
+        },
+        (_, "/tell") | (_, "/respond") | (_, "/stats") | (_, "/params") | (_, "/save") => {
+            return Err(fail(405, "usage", "Method not allowed".to_string()));
+        },
+        _ => return Err(fail(404, "not_found", "No such endpoint".to_string())),
+    };
+    // A prompt is told to the lexicon, which only those allowed to `tell` may do
+    let needed = match decision {
+        Decision::RespondTo(_, Some(_)) => Permission::required("tell"),
+        _ => Permission::required(command),
+    };
+    if permission < needed {
+        println!("Warning: Rejected HTTP request for `{}` with {} permission", path, permission.name());
+        return Err(fail(403, "permission_denied", format!("`{}` needs {} permission", path, needed.name())));
+    }
+
+    let messages = try!(run(commands, decision));
+    let encoded = match command {
+        "respond" => serde_json::to_string(&ReplyBody{reply: messages.into_iter().next()}),
+        "stats" => serde_json::to_string(&fields(&messages)),
+        // The main loop already encodes the values
+        "get" => return Ok(messages.into_iter().next().unwrap_or_else(String::new)),
+        // Show what the values are now
+        "set" => {
+            let messages = try!(run(commands, Decision::GetParams));
+            return Ok(messages.into_iter().next().unwrap_or_else(String::new));
+        },
+        _ => serde_json::to_string(&MessagesBody{messages: messages}),
+    };
+    encoded.map_err(|e| fail(500, "io", format!("Unable to encode response: {}", e)))
+}
+
+/// Get the token from an `Authorization: Bearer <token>` header
+fn token(request: &Request) -> Option<String> {
+    request.headers().iter()
+        .find(|h| h.field.equiv("Authorization"))
+        .and_then(|h| {
+            let value = h.value.as_str();
+            if value.starts_with("Bearer ") {
+                Some(value["Bearer ".len()..].trim().to_string())
+            } else {
+                None
+            }
+        })
+}
+
+fn parse<T: serde::Deserialize>(body: &str) -> Result<T, Failure> {
+    serde_json::from_str(body).map_err(|e| fail(400, "usage", format!("Invalid request body: {}", e)))
+}
+
+/// Make a decision and get the messages it replied with, or the first error if it failed
+fn run(commands: &Commands, decision: Decision) -> Result<Vec<String>, Failure> {
+    let replies = try!(commands.run(decision).map_err(|e| fail(503, "unavailable", e)));
+    let failed = replies.last().and_then(|r| r.code.clone());
+    if let Some(code) = failed {
+        let message = replies.iter()
+            .find(|r| r.code.as_ref() == Some(&code))
+            .and_then(|r| r.payload.clone())
+            .unwrap_or_else(String::new);
+        let status = match code.as_str() {
+            "usage" | "invalid_value" => 400,
+            "not_found" => 404,
+            _ => 500,
+        };
+        return Err((status, code, message));
+    }
+    Ok(replies.into_iter().filter(|r| r.status == STATUS_OK).filter_map(|r| r.payload).collect())
+}
+
+/// Turn `name: value` lines like the ones from `stats` into an object
+fn fields(messages: &[String]) -> BTreeMap<String, Value> {
+    messages.iter().filter_map(|m| {
+        let mut parts = m.splitn(2, ": ");
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => {
+                let value = value.parse().map(Value::U64).unwrap_or_else(|_| Value::String(value.to_string()));
+                Some((name.to_string(), value))
+            },
+            _ => None,
+        }
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind, serve, wake};
+    use cli::{Commands, Decision};
+    use cli::auth::Auth;
+
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+
+    /// An API on its own port, answered by a stand-in for the main loop
+    struct Api {
+        address: String,
+        stop: Arc<AtomicBool>,
+    }
+
+    impl Api {
+        fn start() -> Api {
+            let server = bind("127.0.0.1:0").unwrap();
+            let address = server.server_addr().to_string();
+            let (commands, decisions) = Commands::with_receiver();
+            thread::spawn(move || {
+                for (decision, mut socket) in decisions {
+                    match decision {
+                        Decision::Stats => {
+                            socket.msg("words: 3");
+                            socket.msg("busiest: #rust");
+                        },
+                        Decision::RespondTo(source, _) => socket.msg(&format!("hello {}", source)),
+                        Decision::GetParams => socket.msg(r#"{"cocategorization_ratio":0.5}"#),
+                        Decision::Save(None) => socket.error("usage", "Ignored: No snapshot path is configured"),
+                        Decision::Save(Some(path)) => socket.msg(&format!("Saved snapshot to `{}`", path)),
+                        _ => {},
+                    }
+                }
+            });
+            let auth = Auth{
+                admin_token: Some("admin".to_string()),
+                read_token: Some("read".to_string()),
+            };
+            let stop = Arc::new(AtomicBool::new(false));
+            let serve_stop = stop.clone();
+            thread::spawn(move || serve(server, commands, auth, serve_stop));
+            Api{
+                address: address,
+                stop: stop,
+            }
+        }
+
+        /// Make a request and get the status and body of the response
+        fn request(&self, method: &str, path: &str, token: Option<&str>, body: &str) -> (u16, String) {
+            let mut stream = TcpStream::connect(&*self.address).unwrap();
+            let authorization = token.map(|t| format!("Authorization: Bearer {}\r\n", t)).unwrap_or_else(String::new);
+            write!(stream, "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n{}Content-Length: {}\r\n\r\n{}",
+                method, path, authorization, body.len(), body).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let status = response.split_whitespace().nth(1).and_then(|s| s.parse().ok()).unwrap();
+            let body = response.find("\r\n\r\n").map(|i| response[i + 4..].to_string()).unwrap_or_else(String::new);
+            (status, body)
+        }
+    }
+
+    impl Drop for Api {
+        fn drop(&mut self) {
+            self.stop.store(true, Ordering::SeqCst);
+            wake(&self.address);
+        }
+    }
+
+    #[test]
+    fn tell() {
+        let api = Api::start();
+        let body = r#"{"source": "#rust", "author": "alice", "message": "hi"}"#;
+        assert_eq!(api.request("POST", "/tell", Some("admin"), body), (200, r#"{"messages":[]}"#.to_string()));
+        assert_eq!(api.request("POST", "/tell", Some("read"), body).0, 403);
+        assert_eq!(api.request("POST", "/tell", None, body).0, 403);
+        assert_eq!(api.request("POST", "/tell", Some("admin"), "{").0, 400);
+    }
+
+    #[test]
+    fn respond() {
+        let api = Api::start();
+        assert_eq!(api.request("POST", "/respond", Some("read"), r#"{"source": "#rust"}"#),
+            (200, r#"{"reply":"hello #rust"}"#.to_string()));
+        // Prompts are told to the lexicon
+        let prompted = r#"{"source": "#rust", "prompt": "hi"}"#;
+        assert_eq!(api.request("POST", "/respond", Some("read"), prompted).0, 403);
+        assert_eq!(api.request("POST", "/respond", Some("admin"), prompted).0, 200);
+    }
+
+    #[test]
+    fn stats() {
+        let api = Api::start();
+        assert_eq!(api.request("GET", "/stats", Some("read"), ""),
+            (200, r#"{"busiest":"#rust","words":3}"#.to_string()));
+        assert_eq!(api.request("GET", "/stats", None, "").0, 403);
+    }
+
+    #[test]
+    fn params() {
+        let api = Api::start();
+        let values = r#"{"cocategorization_ratio":0.5}"#.to_string();
+        assert_eq!(api.request("GET", "/params", Some("read"), ""), (200, values.clone()));
+        assert_eq!(api.request("PUT", "/params", Some("admin"), r#"{"cocategorization_ratio": 0.5}"#), (200, values));
+        assert_eq!(api.request("PUT", "/params", Some("read"), "{}").0, 403);
+    }
+
+    #[test]
+    fn save() {
+        let api = Api::start();
+        assert_eq!(api.request("POST", "/save", Some("admin"), r#"{"path": "snapshot.json"}"#),
+            (200, r#"{"messages":["Saved snapshot to `snapshot.json`"]}"#.to_string()));
+        // Errors from the main loop keep their code
+        let (status, body) = api.request("POST", "/save", Some("admin"), "");
+        assert_eq!(status, 400);
+        assert!(body.contains(r#""error":"usage""#));
+    }
+
+    #[test]
+    fn failures() {
+        let api = Api::start();
+        assert_eq!(api.request("GET", "/stats", Some("wrong"), "").0, 401);
+        assert_eq!(api.request("GET", "/nothing", Some("admin"), "").0, 404);
+        assert_eq!(api.request("GET", "/tell", Some("admin"), "").0, 405);
+        assert_eq!(api.request("DELETE", "/params", Some("admin"), "").0, 405);
+    }
+}
@@ -0,0 +1,294 @@
+use std::path::Path;
+use super::{SECONDS_PER_DAY, parse_clock, parse_date};
+
+/// Characters IRC clients put in front of nicks to show channel modes
+const MODE_PREFIXES: &'static [char] = &['~', '&', '@', '%', '+', ' '];
+
+#[derive(Clone, Copy, Debug)]
+pub enum Format {
+    Irssi,
+    Weechat,
+    Znc,
+}
+
+impl Format {
+    pub fn parse(s: &str) -> Option<Format> {
+        match s {
+            "irssi" => Some(Format::Irssi),
+            "weechat" => Some(Format::Weechat),
+            "znc" => Some(Format::Znc),
+            _ => None,
+        }
+    }
+}
+
+/// A line somebody said in the channel
+pub struct Entry {
+    /// Seconds since the start of the log; only meaningful relative to other entries in the same log
+    pub time: Option<u64>,
+    pub nick: String,
+    pub message: String,
+}
+
+/// Turns lines of a log into entries, keeping track of day changes along the way.
+pub struct Parser {
+    format: Format,
+    day: u64,
+    last_clock: u64,
+}
+
+impl Parser {
+    pub fn new(format: Format) -> Parser {
+        Parser::resume(format, (0, 0))
+    }
+
+    /// Pick up partway through a log with the day count and last time of day that `progress` gave.
+    pub fn resume(format: Format, progress: (u64, u64)) -> Parser {
+        Parser{
+            format: format,
+            day: progress.0,
+            last_clock: progress.1,
+        }
+    }
+
+    /// How many days have passed since the start of the log and the last time of day seen
+    pub fn progress(&self) -> (u64, u64) {
+        (self.day, self.last_clock)
+    }
+
+    /// Parse a line of the log. Joins, parts, notices and other system lines give `None`.
+    pub fn parse(&mut self, line: &str) -> Option<Entry> {
+        match self.format {
+            Format::Irssi => self.parse_irssi(line),
+            Format::Weechat => self.parse_weechat(line),
+            Format::Znc => self.parse_znc(line),
+        }
+    }
+
+    /// Turn a time of day into a time since the start of the log
+    fn time(&mut self, clock: u64) -> u64 {
+        // Logs without date markers can only be followed by noticing the clock going backwards
+        if clock < self.last_clock {
+            self.day += 1;
+        }
+        self.last_clock = clock;
+        self.day * SECONDS_PER_DAY + clock
+    }
+
+    /// `12:34 <@nick> message`
+    fn parse_irssi(&mut self, line: &str) -> Option<Entry> {
+        if line.starts_with("--- Day changed") {
+            self.day += 1;
+            self.last_clock = 0;
+            return None;
+        }
+        let (clock, rest) = split_once(line, ' ');
+        let clock = match parse_clock(clock) {
+            Some(c) => c,
+            None => return None,
+        };
+        let time = self.time(clock);
+        user_line(rest).map(|(nick, message)| Entry{
+            time: Some(time),
+            nick: nick,
+            message: message,
+        })
+    }
+
+    /// `2016-01-01 12:34:56\t@nick\tmessage`
+    fn parse_weechat(&mut self, line: &str) -> Option<Entry> {
+        let mut columns = line.splitn(3, '\t');
+        let (date, prefix, message) = match (columns.next(), columns.next(), columns.next()) {
+            (Some(d), Some(p), Some(m)) => (d, p, m),
+            _ => return None,
+        };
+        let time = {
+            let (day, clock) = split_once(date, ' ');
+            match (parse_date(day), parse_clock(clock)) {
+                (Some(d), Some(c)) => Some(d * SECONDS_PER_DAY + c),
+                _ => None,
+            }
+        };
+        let prefix = prefix.trim();
+        match prefix {
+            // Joins, parts, network messages and errors
+            "" | "-->" | "<--" | "--" | "=!=" => None,
+            // Actions put the nick at the start of the message
+            "*" => {
+                let (nick, message) = split_once(message, ' ');
+                entry(time, nick, message)
+            },
+            nick => entry(time, nick, message),
+        }
+    }
+
+    /// `[12:34:56] <nick> message`
+    fn parse_znc(&mut self, line: &str) -> Option<Entry> {
+        if !line.starts_with('[') {
+            return None;
+        }
+        let (clock, rest) = split_once(&line[1..], ']');
+        let clock = match parse_clock(clock) {
+            Some(c) => c,
+            None => return None,
+        };
+        let time = self.time(clock);
+        if rest.trim_left().starts_with("***") {
+            return None;
+        }
+        user_line(rest).map(|(nick, message)| Entry{
+            time: Some(time),
+            nick: nick,
+            message: message,
+        })
+    }
+}
+
+/// Get the channel a log belongs to from its file name
+///
+/// Clients name logs things like `#channel.log`, `irc.network.#channel.weechatlog` or
+/// `network/#channel/2016-01-01.log`, so the channel is the part of the path that begins with `#`.
+pub fn channel<P: AsRef<Path>>(path: P) -> String {
+    let path = path.as_ref();
+    for component in path.components().rev() {
+        let s = component.as_os_str().to_string_lossy();
+        if let Some(start) = s.find('#') {
+            let channel = &s[start..];
+            return match channel.rfind(".log").or_else(|| channel.rfind(".weechatlog")) {
+                Some(end) => channel[..end].to_string(),
+                None => channel.to_string(),
+            };
+        }
+    }
+    path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| "console".to_string())
+}
+
+/// Parse the `<nick> message` or `* nick action` part of a line
+fn user_line(rest: &str) -> Option<(String, String)> {
+    let rest = rest.trim_left();
+    if rest.starts_with('<') {
+        match rest.find('>') {
+            Some(end) => {
+                let nick = rest[1..end].trim_left_matches(MODE_PREFIXES);
+                let message = rest[end + 1..].trim_left();
+                if nick.is_empty() || message.is_empty() {
+                    None
+                } else {
+                    Some((nick.to_string(), message.to_string()))
+                }
+            },
+            None => None,
+        }
+    } else if rest.starts_with("* ") {
+        let (nick, message) = split_once(&rest[2..], ' ');
+        if nick.is_empty() || message.is_empty() {
+            None
+        } else {
+            Some((nick.to_string(), message.to_string()))
+        }
+    } else {
+        // Anything else is the client or server talking
+        None
+    }
+}
+
+fn entry(time: Option<u64>, nick: &str, message: &str) -> Option<Entry> {
+    let nick = nick.trim_left_matches(MODE_PREFIXES);
+    if nick.is_empty() || message.is_empty() {
+        None
+    } else {
+        Some(Entry{
+            time: time,
+            nick: nick.to_string(),
+            message: message.to_string(),
+        })
+    }
+}
+
+fn split_once(s: &str, c: char) -> (&str, &str) {
+    match s.find(c) {
+        Some(i) => (&s[..i], &s[i + c.len_utf8()..]),
+        None => (s, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::SECONDS_PER_DAY;
+
+    fn said(entry: Option<Entry>) -> Option<(Option<u64>, String, String)> {
+        entry.map(|e| (e.time, e.nick, e.message))
+    }
+
+    #[test]
+    fn irssi_messages_and_actions() {
+        let mut p = Parser::new(Format::Irssi);
+        assert_eq!(said(p.parse("12:34 <@alice> hello there")),
+            Some((Some(12 * 3600 + 34 * 60), "alice".to_string(), "hello there".to_string())));
+        assert_eq!(said(p.parse("12:35  * bob waves")),
+            Some((Some(12 * 3600 + 35 * 60), "bob".to_string(), "waves".to_string())));
+    }
+
+    #[test]
+    fn irssi_skips_system_lines() {
+        let mut p = Parser::new(Format::Irssi);
+        assert!(p.parse("12:34 -!- alice [~a@host] has joined #channel").is_none());
+        assert!(p.parse("--- Log opened Fri Jan 01 00:00:00 2016").is_none());
+        assert!(p.parse("12:34 <alice>").is_none());
+        assert!(p.parse("not a log line").is_none());
+    }
+
+    #[test]
+    fn irssi_day_changes() {
+        let mut p = Parser::new(Format::Irssi);
+        p.parse("23:59 <alice> late");
+        assert!(p.parse("--- Day changed Sat Jan 02 2016").is_none());
+        assert_eq!(p.parse("00:01 <alice> early").unwrap().time, Some(SECONDS_PER_DAY + 60));
+    }
+
+    #[test]
+    fn clock_going_backwards_is_a_new_day() {
+        let mut p = Parser::new(Format::Znc);
+        p.parse("[23:59:00] <alice> late");
+        assert_eq!(p.parse("[00:00:30] <alice> early").unwrap().time, Some(SECONDS_PER_DAY + 30));
+    }
+
+    #[test]
+    fn resume_keeps_the_day() {
+        let mut p = Parser::new(Format::Irssi);
+        p.parse("--- Day changed Sat Jan 02 2016");
+        p.parse("12:00 <alice> noon");
+        let mut resumed = Parser::resume(Format::Irssi, p.progress());
+        assert_eq!(resumed.parse("11:00 <alice> next morning").unwrap().time, Some(2 * SECONDS_PER_DAY + 11 * 3600));
+    }
+
+    #[test]
+    fn weechat_columns() {
+        let mut p = Parser::new(Format::Weechat);
+        assert_eq!(said(p.parse("1970-01-02 00:00:10\t@alice\thi\tthere")),
+            Some((Some(SECONDS_PER_DAY + 10), "alice".to_string(), "hi\tthere".to_string())));
+        assert_eq!(said(p.parse("1970-01-02 00:00:11\t *\tbob waves")),
+            Some((Some(SECONDS_PER_DAY + 11), "bob".to_string(), "waves".to_string())));
+        assert!(p.parse("1970-01-02 00:00:12\t-->\talice has joined").is_none());
+        assert!(p.parse("1970-01-02 00:00:12\t--\tMode #channel [+o alice]").is_none());
+        assert!(p.parse("no tabs here").is_none());
+    }
+
+    #[test]
+    fn znc_skips_system_lines() {
+        let mut p = Parser::new(Format::Znc);
+        assert_eq!(said(p.parse("[01:02:03] <+carol> hey")),
+            Some((Some(3723), "carol".to_string(), "hey".to_string())));
+        assert!(p.parse("[01:02:04] *** Joins: dave").is_none());
+        assert!(p.parse("01:02:05 <carol> no brackets").is_none());
+    }
+
+    #[test]
+    fn channel_from_path() {
+        assert_eq!(channel("logs/#rust.log"), "#rust");
+        assert_eq!(channel("irc.freenode.#rust.weechatlog"), "#rust");
+        assert_eq!(channel("freenode/#rust/2016-01-01.log"), "#rust");
+        assert_eq!(channel("logs/query.log"), "query");
+    }
+}
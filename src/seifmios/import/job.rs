@@ -0,0 +1,332 @@
+extern crate rand;
+extern crate serde_json;
+use self::serde_json::Value;
+
+use chat::ChatMessage;
+use cli::SocketLend;
+use text::Lexicon;
+use super::{CONVERSATION_GAP, Importer};
+use super::irclog;
+use super::json::{self, FieldMap};
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Seek, SeekFrom};
+use std::str;
+
+/// How many lines or messages a job imports each time it gets a turn on the main loop
+const JOB_BATCH: usize = 64;
+/// Where the offsets of unfinished imports are kept so they can be resumed, unless configured otherwise. It is
+/// only written along with a snapshot, so the offsets always match what the snapshot has learned.
+pub const OFFSETS_PATH: &'static str = "seifmios_imports.json";
+
+#[derive(Clone, Debug)]
+pub enum Kind {
+    Lines,
+    IrcLog(irclog::Format, u64),
+    DiscordExport(FieldMap),
+    JsonLines(FieldMap),
+}
+
+impl Kind {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Kind::Lines => "lines",
+            Kind::IrcLog(..) => "irclog",
+            Kind::DiscordExport(_) => "discord-export",
+            Kind::JsonLines(_) => "jsonl",
+        }
+    }
+}
+
+/// Where an unfinished import got to
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default)]
+struct Offset {
+    /// Bytes into the file
+    position: u64,
+    /// The day count and last time of day of an IRC log parser, which can't be worked out from partway through
+    #[serde(default)]
+    progress: (u64, u64),
+}
+
+/// How each line of a text file becomes a message
+enum LineParser {
+    Lines,
+    IrcLog(irclog::Parser, String),
+    JsonLines(FieldMap),
+}
+
+enum Input {
+    /// Files read a line at a time
+    Text(BufReader<File>, LineParser),
+    /// Discord exports read a message at a time
+    Export(json::ExportReader<BufReader<File>>, FieldMap),
+}
+
+pub struct Job {
+    id: usize,
+    kind: Kind,
+    filename: String,
+    /// Key the offset of this job is saved under, made of its kind and the canonical path of its file
+    key: String,
+    input: Input,
+    importer: Importer,
+    position: u64,
+    total: u64,
+    skipped: usize,
+}
+
+impl Job {
+    fn open(id: usize, kind: Kind, filename: String, key: String, offset: Offset) -> Result<Job, String> {
+        let mut file = try!(File::open(&filename).map_err(|e| format!("Unable to open file: {}", e)));
+        let total = try!(file.metadata().map_err(|e| format!("Unable to read file size: {}", e))).len();
+        // Start over if the file has shrunk since the offset was saved
+        let offset = if offset.position > total { Offset::default() } else { offset };
+        let input = match kind.clone() {
+            Kind::DiscordExport(fields) => {
+                // The document is read up to its messages again to find the channel they are in
+                let mut export = try!(json::ExportReader::new(BufReader::new(file)));
+                if offset.position > export.position() {
+                    try!(export.seek(offset.position));
+                }
+                Input::Export(export, fields)
+            },
+            text => {
+                try!(file.seek(SeekFrom::Start(offset.position)).map_err(|e| format!("Unable to seek in file: {}", e)));
+                let parser = match text {
+                    Kind::IrcLog(format, _) => {
+                        LineParser::IrcLog(irclog::Parser::resume(format, offset.progress), irclog::channel(&filename))
+                    },
+                    Kind::JsonLines(fields) => LineParser::JsonLines(fields),
+                    _ => LineParser::Lines,
+                };
+                Input::Text(BufReader::new(file), parser)
+            },
+        };
+        let gap = match kind {
+            Kind::IrcLog(_, gap) => gap,
+            _ => CONVERSATION_GAP,
+        };
+        // Only some logs have dates in them rather than just times of day
+        let absolute = match kind {
+            Kind::Lines => false,
+            Kind::IrcLog(irclog::Format::Weechat, _) => true,
+            Kind::IrcLog(..) => false,
+            Kind::DiscordExport(_) | Kind::JsonLines(_) => true,
+        };
+        Ok(Job{
+            id: id,
+            kind: kind,
+            filename: filename,
+            key: key,
+            input: input,
+            importer: Importer::new(gap, absolute),
+            position: offset.position,
+            total: total,
+            skipped: 0,
+        })
+    }
+
+    /// Where this job has got to
+    fn offset(&self) -> Offset {
+        Offset{
+            position: self.position,
+            progress: match self.input {
+                Input::Text(_, LineParser::IrcLog(ref p, _)) => p.progress(),
+                _ => (0, 0),
+            },
+        }
+    }
+
+    fn percent(&self) -> f64 {
+        if self.total == 0 {
+            100.0
+        } else {
+            self.position as f64 * 100.0 / self.total as f64
+        }
+    }
+
+    /// Import a batch of messages and return whether there is anything left.
+    fn step<R: rand::Rng>(&mut self, lex: &mut Lexicon<R>) -> bool {
+        match self.input {
+            Input::Text(ref mut reader, ref mut parser) => {
+                let mut buf = Vec::new();
+                for _ in 0..JOB_BATCH {
+                    buf.clear();
+                    match reader.read_until(b'\n', &mut buf) {
+                        Ok(0) => return false,
+                        Ok(n) => {
+                            self.position += n as u64;
+                            let line = match str::from_utf8(&buf) {
+                                Ok(s) => s,
+                                Err(_) => {
+                                    self.skipped += 1;
+                                    continue;
+                                },
+                            };
+                            let line = line.trim_right_matches(&['\r', '\n'][..]);
+                            let message = match *parser {
+                                LineParser::Lines => Some((ChatMessage{
+                                    source: "console".to_string(),
+                                    author: self.filename.clone(),
+                                    message: line.to_string(),
+                                }, None)),
+                                LineParser::IrcLog(ref mut p, ref channel) => match p.parse(line) {
+                                    Some(entry) => Some((ChatMessage{
+                                        source: channel.clone(),
+                                        author: entry.nick,
+                                        message: entry.message,
+                                    }, entry.time)),
+                                    // IRC logs are full of lines that aren't messages, so those aren't skips
+                                    None => continue,
+                                },
+                                LineParser::JsonLines(ref fields) => {
+                                    if line.trim().is_empty() {
+                                        continue;
+                                    }
+                                    serde_json::from_str::<Value>(line).ok().and_then(|v| fields.message(&v, None))
+                                },
+                            };
+                            match message {
+                                Some((message, time)) => self.importer.tell(lex, message, time),
+                                None => self.skipped += 1,
+                            }
+                        },
+                        Err(e) => {
+                            println!("Warning: Import job {} failed to read `{}`: {}", self.id, self.filename, e);
+                            return false;
+                        },
+                    }
+                }
+                true
+            },
+            Input::Export(ref mut export, ref fields) => {
+                for _ in 0..JOB_BATCH {
+                    match export.next_message() {
+                        Ok(Some(raw)) => {
+                            let message = serde_json::from_reader::<_, Value>(&raw[..]).ok()
+                                .and_then(|v| fields.message(&v, Some(export.parent())));
+                            match message {
+                                Some((message, time)) => self.importer.tell(lex, message, time),
+                                None => self.skipped += 1,
+                            }
+                            self.position = export.position();
+                        },
+                        Ok(None) => return false,
+                        Err(e) => {
+                            println!("Warning: Import job {} failed to read `{}`: {}", self.id, self.filename, e);
+                            return false;
+                        },
+                    }
+                }
+                true
+            },
+        }
+    }
+}
+
+/// All of the imports that are running in the background
+pub struct Jobs {
+    next_id: usize,
+    jobs: Vec<Job>,
+    /// Index of the job that gets the next turn
+    turn: usize,
+    offsets: BTreeMap<String, Offset>,
+    offsets_path: String,
+}
+
+impl Jobs {
+    /// Read the offsets saved with the snapshot, unless no snapshot was loaded, in which case nothing imported
+    /// before has been kept and every import starts over.
+    pub fn new(offsets_path: String, resume: bool) -> Jobs {
+        let offsets = if resume {
+            File::open(&offsets_path)
+                .ok()
+                .and_then(|f| serde_json::from_reader(f).ok())
+                .unwrap_or_else(BTreeMap::new)
+        } else {
+            BTreeMap::new()
+        };
+        Jobs{
+            next_id: 1,
+            jobs: Vec::new(),
+            turn: 0,
+            offsets: offsets,
+            offsets_path: offsets_path,
+        }
+    }
+
+    /// Start importing a file, resuming from where a previous import of it left off. Gives the job ID and the
+    /// offset it started at.
+    pub fn start(&mut self, kind: Kind, filename: String) -> Result<(usize, u64), String> {
+        // The same file can be named many ways, so jobs go by where it really is
+        let path = try!(fs::canonicalize(&filename).map_err(|e| format!("Unable to open file: {}", e)));
+        let key = format!("{} {}", kind.name(), path.display());
+        if self.jobs.iter().any(|j| j.key == key) {
+            return Err("That file is already being imported".to_string());
+        }
+        let offset = self.offsets.get(&key).cloned().unwrap_or_else(Offset::default);
+        let id = self.next_id;
+        let job = try!(Job::open(id, kind, filename, key, offset));
+        let started = job.position;
+        self.next_id += 1;
+        self.jobs.push(job);
+        Ok((id, started))
+    }
+
+    /// Stop a job, keeping its offset so importing the same file again resumes it.
+    pub fn cancel(&mut self, id: usize) -> Option<u64> {
+        match self.jobs.iter().position(|j| j.id == id) {
+            Some(index) => {
+                let job = self.jobs.remove(index);
+                self.offsets.insert(job.key.clone(), job.offset());
+                Some(job.position)
+            },
+            None => None,
+        }
+    }
+
+    pub fn list(&self, socket: &mut SocketLend) {
+        if self.jobs.is_empty() {
+            socket.msg("No import jobs running");
+        }
+        for job in &self.jobs {
+            socket.msg(&format!("Job {}: {} `{}` {:.1}% ({} messages, {} skipped)",
+                job.id, job.kind.name(), job.filename, job.percent(), job.importer.count(), job.skipped));
+        }
+    }
+
+    /// Give the next job a turn. Returns false if there was nothing to do.
+    pub fn step<R: rand::Rng>(&mut self, lex: &mut Lexicon<R>) -> bool {
+        if self.jobs.is_empty() {
+            return false;
+        }
+        self.turn %= self.jobs.len();
+        let more = self.jobs[self.turn].step(lex);
+        if more {
+            self.turn += 1;
+        } else {
+            let job = self.jobs.remove(self.turn);
+            println!("Import job {} of `{}` finished with {} messages ({} skipped)",
+                job.id, job.filename, job.importer.count(), job.skipped);
+            self.offsets.remove(&job.key);
+        }
+        true
+    }
+
+    /// Remember where every running job is so they can be resumed after a restart. This must only be done right
+    /// after saving a snapshot, since resuming skips everything the snapshot is assumed to have learned.
+    pub fn save_offsets(&mut self) {
+        for job in &self.jobs {
+            self.offsets.insert(job.key.clone(), job.offset());
+        }
+        match File::create(&self.offsets_path) {
+            Ok(mut f) => {
+                serde_json::to_writer(&mut f, &self.offsets).unwrap_or_else(|e| {
+                    println!("Warning: Unable to write import offsets: {}", e);
+                });
+            },
+            Err(e) => println!("Warning: Unable to create import offsets file: {}", e),
+        }
+    }
+}
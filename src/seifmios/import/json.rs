@@ -0,0 +1,362 @@
+extern crate serde_json;
+use self::serde_json::Value;
+
+use chat::ChatMessage;
+use super::parse_timestamp;
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Seek, SeekFrom};
+
+/// Which (dot separated) fields of a JSON object hold each part of a message
+#[derive(Clone, Debug)]
+pub struct FieldMap {
+    pub source: String,
+    pub author: String,
+    pub content: String,
+    pub timestamp: String,
+}
+
+impl FieldMap {
+    /// Fields of JSON lines default to the shape of a `ChatMessage`.
+    pub fn jsonl() -> FieldMap {
+        FieldMap{
+            source: "source".to_string(),
+            author: "author".to_string(),
+            content: "message".to_string(),
+            timestamp: "timestamp".to_string(),
+        }
+    }
+
+    /// Fields of a Discord export default to what the Discord adapter uses, so imported channels continue the
+    /// sources of the live ones.
+    pub fn discord_export() -> FieldMap {
+        FieldMap{
+            source: "channel.id".to_string(),
+            author: "author.name".to_string(),
+            content: "content".to_string(),
+            timestamp: "timestamp".to_string(),
+        }
+    }
+
+    /// Change a field from an assignment like `author=user.nick`.
+    pub fn set(&mut self, assignment: &str) -> Result<(), String> {
+        let (key, path) = match assignment.find('=') {
+            Some(i) => (&assignment[..i], assignment[i + 1..].to_string()),
+            None => return Err(format!("Expected <field>=<path> but got `{}`", assignment)),
+        };
+        match key {
+            "source" => self.source = path,
+            "author" => self.author = path,
+            "content" => self.content = path,
+            "timestamp" => self.timestamp = path,
+            _ => return Err(format!("Unrecognized field `{}`; fields are source, author, content, timestamp", key)),
+        }
+        Ok(())
+    }
+
+    /// Pull a message out of a JSON object. Fields missing from the object are looked up in `parent` instead,
+    /// which is how messages in an export find out what channel they are in.
+    pub fn message(&self, value: &Value, parent: Option<&Value>) -> Option<(ChatMessage, Option<u64>)> {
+        let get = |path: &str| lookup(value, path).or_else(|| parent.and_then(|p| lookup(p, path)));
+        let source = match get(&self.source).and_then(string) {
+            Some(s) => s,
+            None => return None,
+        };
+        let author = match get(&self.author).and_then(string) {
+            Some(s) => s,
+            None => return None,
+        };
+        let content = match get(&self.content).and_then(|v| v.as_str()) {
+            Some(s) => s.to_string(),
+            None => return None,
+        };
+        let time = get(&self.timestamp).and_then(timestamp);
+        Some((ChatMessage{
+            source: source,
+            author: author,
+            message: content,
+        }, time))
+    }
+}
+
+/// Reads the messages of a Discord export one at a time, so exports too large to hold in memory can be imported
+/// and resumed by their position in bytes. The fields of the document that come before the messages array are
+/// kept as the parent of each message.
+pub struct ExportReader<R> {
+    reader: R,
+    parent: Value,
+    /// Bytes read so far, which is just after the last message read
+    position: u64,
+}
+
+impl<R: BufRead> ExportReader<R> {
+    /// Read a Discord export up to its first message, making sure it has a messages array.
+    pub fn new(reader: R) -> Result<ExportReader<R>, String> {
+        let mut export = ExportReader{
+            reader: reader,
+            parent: Value::Object(BTreeMap::new()),
+            position: 0,
+        };
+        try!(export.expect(b'{'));
+        loop {
+            if try!(export.skip_whitespace()) != Some(b'"') {
+                return Err("Document has no messages array".to_string());
+            }
+            let key: String = try!(export.value().and_then(|raw| {
+                serde_json::from_reader(&raw[..]).map_err(|e| format!("Invalid JSON: {}", e))
+            }));
+            try!(export.expect(b':'));
+            if key == "messages" {
+                try!(export.expect(b'['));
+                return Ok(export);
+            }
+            try!(export.skip_whitespace());
+            let value: Value = try!(export.value().and_then(|raw| {
+                serde_json::from_reader(&raw[..]).map_err(|e| format!("Invalid JSON: {}", e))
+            }));
+            if let Value::Object(ref mut fields) = export.parent {
+                fields.insert(key, value);
+            }
+            try!(export.expect(b','));
+        }
+    }
+
+    /// The bytes of the next message, which may not be valid JSON, or None once the messages array has ended.
+    pub fn next_message(&mut self) -> Result<Option<Vec<u8>>, String> {
+        match try!(self.skip_whitespace()) {
+            Some(b']') => return Ok(None),
+            Some(b',') => {
+                self.consume();
+                try!(self.skip_whitespace());
+            },
+            Some(_) => {},
+            None => return Err("Unexpected end of file".to_string()),
+        }
+        self.value().map(Some)
+    }
+
+    /// The document the messages are in, without the messages
+    pub fn parent(&self) -> &Value {
+        &self.parent
+    }
+
+    pub fn position(&self) -> u64 {
+        self.position
+    }
+
+    fn peek(&mut self) -> Result<Option<u8>, String> {
+        match self.reader.fill_buf() {
+            Ok(buf) => Ok(buf.first().cloned()),
+            Err(e) => Err(format!("Unable to read file: {}", e)),
+        }
+    }
+
+    fn consume(&mut self) {
+        self.reader.consume(1);
+        self.position += 1;
+    }
+
+    /// Skip to the next byte that isn't whitespace and give it without consuming it.
+    fn skip_whitespace(&mut self) -> Result<Option<u8>, String> {
+        loop {
+            match try!(self.peek()) {
+                Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') => self.consume(),
+                b => return Ok(b),
+            }
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), String> {
+        match try!(self.skip_whitespace()) {
+            Some(b) if b == byte => {
+                self.consume();
+                Ok(())
+            },
+            Some(b) => Err(format!("Expected `{}` but got `{}` at byte {}", byte as char, b as char, self.position)),
+            None => Err("Unexpected end of file".to_string()),
+        }
+    }
+
+    /// Read the bytes of the JSON value that starts here without parsing it, just by matching up brackets and
+    /// quotes.
+    fn value(&mut self) -> Result<Vec<u8>, String> {
+        let mut raw = Vec::new();
+        let mut depth = 0;
+        let mut string = false;
+        let mut escaped = false;
+        loop {
+            let b = match try!(self.peek()) {
+                Some(b) => b,
+                None => return Err("Unexpected end of file".to_string()),
+            };
+            if string {
+                if escaped {
+                    escaped = false;
+                } else if b == b'\\' {
+                    escaped = true;
+                } else if b == b'"' {
+                    string = false;
+                }
+            } else {
+                match b {
+                    b'"' => string = true,
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' if depth > 0 => depth -= 1,
+                    // Anything else ends a number or literal, and belongs to whatever the value is in
+                    b'}' | b']' | b',' | b' ' | b'\t' | b'\n' | b'\r' if depth == 0 => return Ok(raw),
+                    _ => {},
+                }
+            }
+            raw.push(b);
+            self.consume();
+            if depth == 0 && !string && (b == b'"' || b == b'}' || b == b']') {
+                return Ok(raw);
+            }
+        }
+    }
+}
+
+impl<R: BufRead + Seek> ExportReader<R> {
+    /// Carry on reading messages from a position given earlier by `position`.
+    pub fn seek(&mut self, position: u64) -> Result<(), String> {
+        try!(self.reader.seek(SeekFrom::Start(position)).map_err(|e| format!("Unable to seek in file: {}", e)));
+        self.position = position;
+        Ok(())
+    }
+}
+
+/// Follow a dot separated path through nested objects
+fn lookup<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut value = value;
+    for key in path.split('.') {
+        value = match value.as_object().and_then(|o| o.get(key)) {
+            Some(v) => v,
+            None => return None,
+        };
+    }
+    Some(value)
+}
+
+/// IDs may be strings or numbers, but both make a fine name
+fn string(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.clone()),
+        Value::I64(n) => Some(n.to_string()),
+        Value::U64(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+/// Timestamps may be text or seconds (or milliseconds) since 1970
+fn timestamp(value: &Value) -> Option<u64> {
+    match *value {
+        Value::String(ref s) => parse_timestamp(s),
+        Value::I64(n) if n >= 0 => Some(seconds(n as u64)),
+        Value::U64(n) => Some(seconds(n)),
+        Value::F64(n) if n >= 0.0 => Some(seconds(n as u64)),
+        _ => None,
+    }
+}
+
+/// Anything too large to be seconds is assumed to be milliseconds
+fn seconds(n: u64) -> u64 {
+    if n > 100_000_000_000 { n / 1000 } else { n }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::serde_json::{self, Value};
+
+    use std::io::{BufRead, Cursor};
+
+    fn value(s: &str) -> Value {
+        serde_json::from_str(s).unwrap()
+    }
+
+    fn said(fields: &FieldMap, value: &Value, parent: Option<&Value>) -> Option<(String, String, String, Option<u64>)> {
+        fields.message(value, parent).map(|(m, time)| (m.source, m.author, m.message, time))
+    }
+
+    #[test]
+    fn jsonl_defaults() {
+        let line = value(r#"{"source": "#rust", "author": "alice", "message": "hi", "timestamp": 86401}"#);
+        assert_eq!(said(&FieldMap::jsonl(), &line, None),
+            Some(("#rust".to_string(), "alice".to_string(), "hi".to_string(), Some(86401))));
+    }
+
+    #[test]
+    fn missing_fields() {
+        let fields = FieldMap::jsonl();
+        assert_eq!(said(&fields, &value(r#"{"source": "a", "author": "b"}"#), None), None);
+        // Only the timestamp is optional
+        assert_eq!(said(&fields, &value(r#"{"source": "a", "author": "b", "message": "c"}"#), None),
+            Some(("a".to_string(), "b".to_string(), "c".to_string(), None)));
+    }
+
+    #[test]
+    fn nested_paths_and_parent() {
+        let document = value(r#"{"channel": {"id": 42}, "messages": []}"#);
+        let message = value(r#"{"author": {"name": "alice"}, "content": "hi", "timestamp": "1970-01-02T00:00:01.000+00:00"}"#);
+        assert_eq!(said(&FieldMap::discord_export(), &message, Some(&document)),
+            Some(("42".to_string(), "alice".to_string(), "hi".to_string(), Some(86401))));
+    }
+
+    #[test]
+    fn set_fields() {
+        let mut fields = FieldMap::jsonl();
+        assert!(fields.set("author=user.nick").is_ok());
+        assert_eq!(fields.author, "user.nick");
+        assert!(fields.set("author").is_err());
+        assert!(fields.set("colour=red").is_err());
+    }
+
+    #[test]
+    fn milliseconds() {
+        let line = value(r#"{"source": "a", "author": "b", "message": "c", "timestamp": 86401000}"#);
+        assert_eq!(said(&FieldMap::jsonl(), &line, None).and_then(|s| s.3), Some(86401000));
+        let line = value(r#"{"source": "a", "author": "b", "message": "c", "timestamp": 1451606400000}"#);
+        assert_eq!(said(&FieldMap::jsonl(), &line, None).and_then(|s| s.3), Some(1451606400));
+    }
+
+    fn messages<R: BufRead>(export: &mut ExportReader<R>) -> Vec<Value> {
+        let mut messages = Vec::new();
+        while let Some(raw) = export.next_message().unwrap() {
+            messages.push(serde_json::from_reader(&raw[..]).unwrap());
+        }
+        messages
+    }
+
+    #[test]
+    fn discord_export_needs_messages() {
+        assert!(ExportReader::new(&b"{\"messages\": []}"[..]).is_ok());
+        assert!(ExportReader::new(&b"{\"channel\": {}}"[..]).is_err());
+        assert!(ExportReader::new(&b"not json"[..]).is_err());
+    }
+
+    #[test]
+    fn discord_export_streams() {
+        let document = br#"{"channel": {"id": 42, "name": "a]b"}, "count": 2, "messages": [
+            {"content": "hi {\"there\"}", "n": [1, 2]}, {"content": "bye", "n": -1.5}
+        ], "after": true}"#;
+        let mut export = ExportReader::new(&document[..]).unwrap();
+        assert_eq!(export.parent(), &value(r#"{"channel": {"id": 42, "name": "a]b"}, "count": 2}"#));
+        assert_eq!(messages(&mut export), vec![
+            value(r#"{"content": "hi {\"there\"}", "n": [1, 2]}"#),
+            value(r#"{"content": "bye", "n": -1.5}"#),
+        ]);
+        assert_eq!(export.next_message(), Ok(None));
+    }
+
+    #[test]
+    fn discord_export_resumes() {
+        let document = br#"{"channel": {"id": 42}, "messages": [{"content": "a"}, {"content": "b"}, {"content": "c"}]}"#;
+        let mut export = ExportReader::new(Cursor::new(&document[..])).unwrap();
+        export.next_message().unwrap();
+        let position = export.position();
+        let mut resumed = ExportReader::new(Cursor::new(&document[..])).unwrap();
+        resumed.seek(position).unwrap();
+        assert_eq!(resumed.parent(), &value(r#"{"channel": {"id": 42}}"#));
+        assert_eq!(messages(&mut resumed), vec![value(r#"{"content": "b"}"#), value(r#"{"content": "c"}"#)]);
+    }
+}
@@ -0,0 +1,160 @@
+extern crate rand;
+
+pub mod irclog;
+pub mod json;
+pub mod job;
+
+use chat::ChatMessage;
+use text::Lexicon;
+
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+
+/// Default amount of silence in seconds after which a new conversation is started
+pub const CONVERSATION_GAP: u64 = 30 * 60;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Tells imported messages to the lexicon, starting a new conversation in a source whenever it has been quiet for
+/// long enough.
+pub struct Importer {
+    gap: u64,
+    // Whether times are seconds since 1970 rather than just relative to each other
+    absolute: bool,
+    last_times: BTreeMap<String, Option<u64>>,
+    count: usize,
+}
+
+impl Importer {
+    pub fn new(gap: u64, absolute: bool) -> Importer {
+        Importer{
+            gap: gap,
+            absolute: absolute,
+            last_times: BTreeMap::new(),
+            count: 0,
+        }
+    }
+
+    /// Tell a message that was said at `time` seconds, if that is known.
+    pub fn tell<R: rand::Rng>(&mut self, lex: &mut Lexicon<R>, message: ChatMessage, time: Option<u64>) {
+        let source = lex.source(message.source.clone());
+        match self.last_times.entry(message.source) {
+            Entry::Vacant(v) => {
+                // Don't continue whatever conversation was last going on in this source
+                lex.switch(source.clone());
+                v.insert(time);
+            },
+            Entry::Occupied(mut o) => {
+                // A long silence (or the clock going backwards) starts a new conversation
+                if let (Some(last), Some(time)) = (*o.get(), time) {
+                    if time < last || time - last >= self.gap {
+                        lex.switch(source.clone());
+                    }
+                }
+                if time.is_some() {
+                    o.insert(time);
+                }
+            },
+        }
+        if !message.message.is_empty() {
+            let author = lex.author(source.clone(), message.author);
+            match (self.absolute, time) {
+                (true, Some(time)) => lex.tell_at(source, author, message.message, time),
+                _ => lex.tell(source, author, message.message),
+            }
+            self.count += 1;
+        }
+    }
+
+    /// How many messages have been told so far
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+/// Parse a timestamp like `2016-01-01T12:34:56.789+00:00` or `2016-01-01 12:34:56` into seconds since 1970.
+///
+/// Time zone offsets are ignored since only the time between messages matters.
+pub fn parse_timestamp(s: &str) -> Option<u64> {
+    let (date, clock) = match s.find(|c| c == 'T' || c == ' ') {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s, ""),
+    };
+    let clock = match clock.find(|c| c == '.' || c == '+' || c == '-' || c == 'Z') {
+        Some(i) => &clock[..i],
+        None => clock,
+    };
+    match (parse_date(date), parse_clock(clock)) {
+        (Some(d), Some(c)) => Some(d * SECONDS_PER_DAY + c),
+        (Some(d), None) => Some(d * SECONDS_PER_DAY),
+        _ => None,
+    }
+}
+
+/// Parse `HH:MM` or `HH:MM:SS` into seconds since midnight
+pub fn parse_clock(s: &str) -> Option<u64> {
+    let parts = match s.split(':').map(|p| p.parse::<u64>()).collect::<Result<Vec<_>, _>>() {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+    match parts.len() {
+        2 => Some(parts[0] * 3600 + parts[1] * 60),
+        3 => Some(parts[0] * 3600 + parts[1] * 60 + parts[2]),
+        _ => None,
+    }
+}
+
+/// Parse `YYYY-MM-DD` into days since 1970-01-01
+pub fn parse_date(s: &str) -> Option<u64> {
+    let parts = match s.split('-').map(|p| p.parse::<i64>()).collect::<Result<Vec<_>, _>>() {
+        Ok(p) => p,
+        Err(_) => return None,
+    };
+    if parts.len() != 3 {
+        return None;
+    }
+    let (y, m, d) = (parts[0], parts[1], parts[2]);
+    // Count years from March so the leap day is at the end of the year
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = (if y >= 0 { y } else { y - 399 }) / 400;
+    let yoe = y - era * 400;
+    let doy = (153 * (if m > 2 { m - 3 } else { m + 9 }) + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    if days < 0 { None } else { Some(days as u64) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SECONDS_PER_DAY, parse_clock, parse_date, parse_timestamp};
+
+    #[test]
+    fn clocks() {
+        assert_eq!(parse_clock("12:34"), Some(12 * 3600 + 34 * 60));
+        assert_eq!(parse_clock("12:34:56"), Some(12 * 3600 + 34 * 60 + 56));
+        assert_eq!(parse_clock("12"), None);
+        assert_eq!(parse_clock("ab:cd"), None);
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(parse_date("1970-01-01"), Some(0));
+        assert_eq!(parse_date("2000-03-01"), Some(11017));
+        assert_eq!(parse_date("2016-01-01"), Some(16801));
+        // Leap day
+        assert_eq!(parse_date("2016-03-01"), Some(16801 + 31 + 29));
+        assert_eq!(parse_date("1969-12-31"), None);
+        assert_eq!(parse_date("2016-01"), None);
+    }
+
+    #[test]
+    fn timestamps() {
+        let day = 16801 * SECONDS_PER_DAY;
+        assert_eq!(parse_timestamp("2016-01-01T12:34:56.789+00:00"), Some(day + 45296));
+        assert_eq!(parse_timestamp("2016-01-01T12:34:56Z"), Some(day + 45296));
+        assert_eq!(parse_timestamp("2016-01-01T12:34:56-05:00"), Some(day + 45296));
+        assert_eq!(parse_timestamp("2016-01-01 12:34:56"), Some(day + 45296));
+        assert_eq!(parse_timestamp("2016-01-01"), Some(day));
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+}
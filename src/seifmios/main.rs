@@ -3,82 +3,302 @@
 extern crate serde;
 extern crate rand;
 extern crate crossbeam;
+extern crate libc;
+extern crate serde_json;
 
-use std::io::{BufReader, BufRead};
-use std::fs::File;
+use std::env::args;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, ATOMIC_BOOL_INIT, Ordering};
 use std::sync::mpsc::{channel, TryRecvError};
-use std::thread::spawn;
 
 mod text;
 mod cli;
 mod chat;
+mod import;
+mod config;
+mod http;
+
+/// Set by SIGHUP so the main loop knows to reload the config
+static RELOAD: AtomicBool = ATOMIC_BOOL_INIT;
+
+extern "C" fn hangup(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// What the HTTP API uses to reach the main loop
+struct Channels {
+    commands: cli::Commands,
+    auth: cli::auth::Auth,
+}
 
 fn main() {
     use rand::SeedableRng;
-    let mut lex = text::Lexicon::new(rand::Isaac64Rng::from_seed(&[1, 2, 3, 4]));
-    let console = lex.source("console".to_string());
+    let mut config_path = None;
+    let mut endpoint = None;
+    let mut snapshot = None;
+    let mut seed = None;
+    let mut overrides = Vec::new();
+    let mut arguments = args().skip(1);
+    while let Some(arg) = arguments.next() {
+        let mut value = || arguments.next().unwrap_or_else(|| panic!("Error: {} needs a value", arg));
+        match arg.as_str() {
+            "--config" => config_path = Some(value()),
+            "--endpoint" => endpoint = Some(value()),
+            "--snapshot" => snapshot = Some(value()),
+            "--seed" => seed = Some(value().split(',')
+                .map(|n| n.trim().parse::<u64>().unwrap_or_else(|e| panic!("Error: Invalid seed: {}", e)))
+                .collect::<Vec<_>>()),
+            // Override a lexicon value from the config, like `--set cocategorization_ratio=0.5`
+            "--set" => overrides.push(value()),
+            _ => panic!("Error: Unrecognized argument `{}`", arg),
+        }
+    }
+
+    let mut config = config::Config::find(config_path.as_ref().map(|s| s.as_str()))
+        .unwrap_or_else(|e| panic!("Error: {}", e));
+    for o in &overrides {
+        config.lexicon.set(o).unwrap_or_else(|e| panic!("Error: {}", e));
+    }
+    let seed = seed.or(config.seed.take()).unwrap_or_else(|| vec![1, 2, 3, 4]);
+    let snapshot = snapshot.or(config.snapshot.take());
+
+    let mut presets = config::Presets::load(
+        config.presets.clone().unwrap_or_else(|| config::PRESETS_PATH.to_string()))
+        .unwrap_or_else(|e| panic!("Error: {}", e));
+
+    let mut lex = text::Lexicon::new(rand::Isaac64Rng::from_seed(&seed));
+    apply_preset(&config, &presets, &mut lex).unwrap_or_else(|e| panic!("Error: {}", e));
+    config.lexicon.apply(&mut lex);
+    let loaded = match snapshot {
+        Some(ref path) if Path::new(path).exists() => {
+            lex.load(path).unwrap_or_else(|e| panic!("Error: Unable to load snapshot `{}`: {}", path, e));
+            println!("Loaded snapshot `{}`", path);
+            true
+        },
+        _ => false,
+    };
+    let console = lex.source(config.console.clone().unwrap_or_else(|| "console".to_string()));
     let me = lex.author(console.clone(), "me".to_string());
     let (sender, receiver) = channel();
-    let mut server_running = false;
-    for response in cli::new() {
+    let mut jobs = import::job::Jobs::new(
+        config.import_offsets.clone().unwrap_or_else(|| import::job::OFFSETS_PATH.to_string()), loaded);
+    let endpoint = cli::endpoint(endpoint, config.endpoint.clone());
+    let auth = cli::auth::Auth::new(config.admin_token.clone(), config.read_token.clone());
+    let commands = cli::new(endpoint, auth.clone());
+    let channels = Channels{
+        commands: commands.commands(),
+        auth: auth,
+    };
+    let mut adapters = chat::Registry::new(sender, channels.commands.clone());
+    for connection in &config.connections {
+        if let Err(e) = connect(connection.clone(), true, &channels, &mut adapters) {
+            println!("Warning: Unable to connect {}: {}", connection.kind, e);
+        }
+    }
+    unsafe {
+        libc::signal(libc::SIGHUP, hangup as libc::sighandler_t);
+    }
+    for response in commands {
         match response {
             Some((decision, mut socket)) => {
                 use cli::Decision;
                 match decision {
                     Decision::Quit => {
+                        if let Some(ref path) = snapshot {
+                            match lex.save(path) {
+                                Ok(_) => jobs.save_offsets(),
+                                Err(e) => println!("Warning: Unable to save snapshot: {}", e),
+                            }
+                        }
                         return;
                     },
-                    Decision::ImportLines(filename) => {
-                        let author = lex.author(console.clone(), filename.clone());
-                        let file = File::open(&filename);
-                        match file {
-                            Ok(f) => {
-                                for (index, line) in BufReader::new(f).lines().enumerate() {
-                                    match line {
-                                        Ok(s) => {
-                                            lex.tell(console.clone(), author.clone(), s);
-                                            if (index + 1) % 10000 == 0 {
-                                                socket.msg(&format!("On line {} of {}", index + 1, filename));
-                                            }
-                                        },
-                                        Err(_) => {
-                                            socket.msg(&format!("Ignored: File had read error on line {}", index + 1));
-                                        },
-                                    }
+                    Decision::Save(path) => {
+                        match path.or(snapshot.clone()) {
+                            Some(path) => {
+                                match lex.save(&path) {
+                                    Ok(_) => {
+                                        // Imports resume from the snapshot loaded at startup, not from other ones
+                                        if Some(&path) == snapshot.as_ref() {
+                                            jobs.save_offsets();
+                                        }
+                                        socket.msg(&format!("Saved snapshot to `{}`", path));
+                                    },
+                                    Err(e) => socket.error("io", &format!("Ignored: {}", e)),
+                                }
+                            },
+                            None => {
+                                socket.error("usage", "Ignored: No snapshot path is configured, so one must be given");
+                            },
+                        }
+                    },
+                    Decision::Reload => {
+                        match reload(config_path.as_ref().map(|s| s.as_str()), &overrides, &presets, &mut lex,
+                            &channels, &mut adapters)
+                        {
+                            Ok(lines) => {
+                                for line in lines {
+                                    socket.msg(&line);
                                 }
                             },
-                            Err(_) => {
-                                socket.msg("Ignored: Unable to open file");
+                            Err(e) => socket.error("reload_failed", &format!("Ignored: {}", e)),
+                        }
+                    },
+                    Decision::PresetSave(name) => {
+                        match presets.insert(name.clone(), config::LexiconConfig::current(&lex)) {
+                            Ok(_) => socket.msg(&format!("Saved preset `{}`", name)),
+                            Err(e) => socket.error("io", &format!("Ignored: {}", e)),
+                        }
+                    },
+                    Decision::PresetLoad(name) => {
+                        match presets.get(&name) {
+                            Some(preset) => {
+                                preset.apply(&mut lex);
+                                socket.msg(&format!("Loaded preset `{}`", name));
+                            },
+                            None => socket.error("not_found", "Ignored: No preset with that name"),
+                        }
+                    },
+                    Decision::PresetList => {
+                        for name in presets.names() {
+                            socket.msg(name);
+                        }
+                    },
+                    Decision::GetAll => {
+                        lex.settings(&mut socket);
+                    },
+                    Decision::Import(kind, filename) => {
+                        match jobs.start(kind, filename) {
+                            // Offsets are only kept with a snapshot, since without one nothing imported is kept
+                            Ok((id, _)) if snapshot.is_none() => {
+                                socket.msg(&format!("Started import job {}; without a snapshot it can't resume after a restart", id));
+                            },
+                            Ok((id, 0)) => {
+                                socket.msg(&format!("Started import job {}", id));
+                            },
+                            Ok((id, offset)) => {
+                                socket.msg(&format!("Started import job {}, resuming from offset {}", id, offset));
+                            },
+                            Err(e) => {
+                                socket.error("import_failed", &format!("Ignored: {}", e));
+                            },
+                        }
+                    },
+                    Decision::Jobs => {
+                        jobs.list(&mut socket);
+                    },
+                    Decision::Cancel(id) => {
+                        match jobs.cancel(id) {
+                            Some(offset) => {
+                                socket.msg(&format!("Cancelled job {} at offset {}; importing the file again resumes it", id, offset));
+                            },
+                            None => {
+                                socket.error("not_found", "Ignored: No job with that ID");
                             },
                         }
                     },
                     Decision::ShowCategories => {
                         lex.show_categories(&mut socket);
                     },
+                    Decision::Stats => {
+                        lex.stats(&mut socket);
+                    },
                     Decision::Respond => {
                         if let Some(s) = lex.respond(console.clone()) {
                             socket.msg(&format!("Original: {}\nResponse: {}", s.0, s.1));
+                            // The console is only spoken in here, so this is as sent as it gets
+                            lex.sent(console.clone(), &s.1);
                         }
                     },
                     Decision::Tell(s) => {
                         lex.tell(console.clone(), me.clone(), s);
                     },
-                    Decision::ConnectServer => {
-                        if server_running {
-                            socket.msg("Ignored: Server already running");
-                        } else {
-                            let sender = sender.clone();
-                            spawn(move || chat::server::listen(sender));
-                            server_running = true;
+                    Decision::TellTo(source, author, message) => {
+                        let source = lex.source(source);
+                        let author = lex.author(source.clone(), author);
+                        lex.tell(source, author, message);
+                    },
+                    Decision::ForgetLast(source, remove) => {
+                        let source = source.map(|s| lex.source(s)).unwrap_or_else(|| console.clone());
+                        lex.forget_last(source, remove, &mut socket);
+                    },
+                    Decision::RespondTo(source, prompt) => {
+                        let source = lex.source(source);
+                        if let Some((author, prompt)) = prompt {
+                            let author = lex.author(source.clone(), author);
+                            lex.switch(source.clone());
+                            if !prompt.is_empty() {
+                                lex.tell(source.clone(), author, prompt);
+                            }
+                        }
+                        if let Some(s) = lex.respond(source.clone()) {
+                            socket.msg(&s.1);
+                            // Whoever asked passes it on themselves, so giving it to them is sending it
+                            lex.sent(source, &s.1);
+                        }
+                    },
+                    Decision::GetParams => {
+                        match serde_json::to_string(&config::LexiconConfig::current(&lex)) {
+                            Ok(s) => socket.msg(&s),
+                            Err(e) => socket.error("io", &format!("Ignored: Unable to encode values: {}", e)),
                         }
                     },
-                    Decision::ConnectIrc(config) => {
-                        let sender = sender.clone();
-                        spawn(move || chat::irc::connect(sender, config));
+                    Decision::SetParams(params) => {
+                        params.apply(&mut lex);
                     },
-                    Decision::ConnectDiscord(config) => {
-                        let sender = sender.clone();
-                        spawn(move || chat::discord::connect(sender, config));
+                    Decision::Connections => {
+                        adapters.list(&mut socket);
+                    },
+                    Decision::Disconnect(id) => {
+                        match adapters.stop(id) {
+                            Some(_) => socket.msg(&format!("Stopped connection {}", id)),
+                            None => socket.error("not_found", "Ignored: No connection with that ID"),
+                        }
+                    },
+                    Decision::Reconnect(id) => {
+                        // Starting again reads its config file again, so new credentials are picked up. It waits
+                        // for the old one to finish, which may need the main loop to answer it first.
+                        match adapters.stop(id) {
+                            Some((connection, configured)) => {
+                                adapters.start_later(connection, configured);
+                                socket.msg(&format!("Stopping connection {}; it starts again once it has finished", id));
+                            },
+                            None => socket.error("not_found", "Ignored: No connection with that ID"),
+                        }
+                    },
+                    Decision::ConnectServer(address) => {
+                        let connection = config::Connection::new("server", None, address);
+                        match connect(connection, false, &channels, &mut adapters) {
+                            Ok(id) => socket.msg(&format!("Started connection {}", id)),
+                            Err(e) => socket.error("connect_failed", &format!("Ignored: {}", e)),
+                        }
+                    },
+                    Decision::ConnectHttp(address) => {
+                        let connection = config::Connection::new("http", None, address);
+                        match connect(connection, false, &channels, &mut adapters) {
+                            Ok(id) => socket.msg(&format!("Started connection {}", id)),
+                            Err(e) => socket.error("connect_failed", &format!("Ignored: {}", e)),
+                        }
+                    },
+                    Decision::ConnectWebSocket(address) => {
+                        let connection = config::Connection::new("websocket", None, address);
+                        match connect(connection, false, &channels, &mut adapters) {
+                            Ok(id) => socket.msg(&format!("Started connection {}", id)),
+                            Err(e) => socket.error("connect_failed", &format!("Ignored: {}", e)),
+                        }
+                    },
+                    Decision::ConnectIrc(path) => {
+                        let connection = config::Connection::new("irc", Some(path), None);
+                        match connect(connection, false, &channels, &mut adapters) {
+                            Ok(id) => socket.msg(&format!("Started connection {}", id)),
+                            Err(e) => socket.error("connect_failed", &format!("Ignored: {}", e)),
+                        }
+                    },
+                    Decision::ConnectDiscord(path) => {
+                        let connection = config::Connection::new("discord", Some(path), None);
+                        match connect(connection, false, &channels, &mut adapters) {
+                            Ok(id) => socket.msg(&format!("Started connection {}", id)),
+                            Err(e) => socket.error("connect_failed", &format!("Ignored: {}", e)),
+                        }
                     },
                     Decision::SetCocategoryRatio(f) => {
                         lex.cocategorization_ratio = f;
@@ -125,11 +345,39 @@ fn main() {
                     Decision::FindRelation(words) => {
                         lex.find_relation(words, &mut socket);
                     },
+                    Decision::FindWord((word, hops)) => {
+                        lex.find_word(word, hops, &mut socket);
+                    },
+                    Decision::ExportGraph(filename, format, min_size, around) => {
+                        lex.export_graph(filename, format, min_size, around, &mut socket);
+                    },
+                    Decision::ExportMessages(filename, format, filter) => {
+                        lex.export_messages(filename, format, filter, &mut socket);
+                    },
                 }
             },
             None => {
                 match receiver.try_recv() {
-                    Ok(chat::ReplyMessage(message, replier)) => {
+                    Ok(chat::ReplyMessage::Initiate(name, reply_sender)) => {
+                        let source = lex.source(name.clone());
+                        reply_sender.send(lex.initiate(source).map(|said| said.1)).unwrap_or_else(|e| {
+                            println!("Warning: Reply sender from {} closed unexpectedly: {}", name, e);
+                        });
+                    },
+                    Ok(chat::ReplyMessage::Rejected(name, text, replier)) => {
+                        let source = lex.source(name.clone());
+                        lex.retract(source.clone(), &text);
+                        if let Some(reply_sender) = replier {
+                            reply_sender.send(lex.respond(source).map(|said| said.1)).unwrap_or_else(|e| {
+                                println!("Warning: Reply sender from {} closed unexpectedly: {}", name, e);
+                            });
+                        }
+                    },
+                    Ok(chat::ReplyMessage::Sent(name, text)) => {
+                        let source = lex.source(name);
+                        lex.sent(source, &text);
+                    },
+                    Ok(chat::ReplyMessage::Heard(message, replier)) => {
                         let source = lex.source(message.source.clone());
                         let author = lex.author(source.clone(), message.author.clone());
                         if let Some(reply_sender) = replier {
@@ -152,10 +400,132 @@ fn main() {
                             }
                         }
                     },
-                    Err(TryRecvError::Empty) => lex.think(),
+                    Err(TryRecvError::Empty) if RELOAD.swap(false, Ordering::SeqCst) => {
+                        match reload(config_path.as_ref().map(|s| s.as_str()), &overrides, &presets, &mut lex,
+                            &channels, &mut adapters)
+                        {
+                            Ok(lines) => {
+                                for line in lines {
+                                    println!("{}", line);
+                                }
+                            },
+                            Err(e) => println!("Warning: Unable to reload config: {}", e),
+                        }
+                    },
+                    Err(TryRecvError::Empty) => {
+                        for (connection, configured) in adapters.reap() {
+                            let kind = connection.kind.clone();
+                            match connect(connection, configured, &channels, &mut adapters) {
+                                Ok(id) => println!("Started {} connection {}", kind, id),
+                                Err(e) => println!("Warning: Unable to connect {}: {}", kind, e),
+                            }
+                        }
+                        // Imports take the place of thinking until they are done
+                        if !jobs.step(&mut lex) {
+                            lex.think();
+                        }
+                    },
                     Err(TryRecvError::Disconnected) => panic!("Fatal: The main sender just disappeared!?"),
                 }
             },
         }
     }
 }
+
+/// Start whatever a connection describes, returning its ID
+fn connect(connection: config::Connection, configured: bool, channels: &Channels, adapters: &mut chat::Registry)
+    -> Result<usize, String>
+{
+    try!(connection.check());
+    let address = connection.address.clone();
+    match (connection.kind.as_str(), connection.config.clone()) {
+        ("server", _) => {
+            let address = address.unwrap_or_else(|| chat::server::DEFAULT_ADDRESS.to_string());
+            let adapter = try!(chat::server::ServerAdapter::bind(&address));
+            println!("Listening on `{}`", address);
+            Ok(adapters.start(connection, configured, adapter))
+        },
+        ("http", _) => {
+            let address = address.unwrap_or_else(|| http::DEFAULT_ADDRESS.to_string());
+            let server = try!(http::bind(&address));
+            println!("HTTP API listening on `{}`", address);
+            let (commands, auth) = (channels.commands.clone(), channels.auth.clone());
+            let waker: Box<Fn() + Send> = Box::new(move || http::wake(&address));
+            Ok(adapters.start_with(connection, configured, Some(waker), move |stop| http::serve(server, commands, auth, stop)))
+        },
+        ("websocket", _) => {
+            let address = address.unwrap_or_else(|| chat::websocket::DEFAULT_ADDRESS.to_string());
+            let adapter = try!(chat::websocket::WebSocketAdapter::bind(&address));
+            println!("WebSocket gateway listening on `{}`", address);
+            Ok(adapters.start(connection, configured, adapter))
+        },
+        ("irc", Some(path)) => {
+            let adapter = try!(chat::irc::IrcAdapter::new(path));
+            Ok(adapters.start(connection, configured, adapter))
+        },
+        ("discord", Some(path)) => {
+            let adapter = try!(chat::discord::DiscordAdapter::new(path));
+            Ok(adapters.start(connection, configured, adapter))
+        },
+        ("irc", None) | ("discord", None) => {
+            Err(format!("{} connections need a config file", connection.kind))
+        },
+        (kind, _) => {
+            Err(format!("Unrecognized connection type `{}`", kind))
+        },
+    }
+}
+
+/// Apply the preset the config names, if it names one
+fn apply_preset<R: rand::Rng>(config: &config::Config, presets: &config::Presets, lex: &mut text::Lexicon<R>)
+    -> Result<(), String>
+{
+    if let Some(ref name) = config.preset {
+        match presets.get(name) {
+            Some(preset) => preset.apply(lex),
+            None => return Err(format!("No preset named `{}`", name)),
+        }
+    }
+    Ok(())
+}
+
+/// Read the config again, applying the lexicon values and starting or stopping the configured connections to
+/// match. Everything learned is kept, and settings only read at startup (seed, endpoint, tokens) are left alone.
+fn reload<R: rand::Rng>(path: Option<&str>, overrides: &[String], presets: &config::Presets,
+    lex: &mut text::Lexicon<R>, channels: &Channels, adapters: &mut chat::Registry)
+    -> Result<Vec<String>, String>
+{
+    let mut config = try!(config::Config::find(path));
+    for o in overrides {
+        try!(config.lexicon.set(o));
+    }
+    try!(apply_preset(&config, presets, lex));
+    config.lexicon.apply(lex);
+
+    let mut lines = Vec::new();
+    let stopped = adapters.stop_unconfigured(&config.connections);
+    let (mut started, mut waiting) = (0, 0);
+    for connection in config.connections {
+        if adapters.is_configured(&connection) {
+            continue;
+        }
+        // A stopped connection may still hold an address a new one wants
+        if adapters.is_stopping() {
+            adapters.start_later(connection, true);
+            waiting += 1;
+            continue;
+        }
+        let kind = connection.kind.clone();
+        match connect(connection, true, channels, adapters) {
+            Ok(_) => started += 1,
+            Err(e) => lines.push(format!("Warning: Unable to connect {}: {}", kind, e)),
+        }
+    }
+    if waiting > 0 {
+        lines.push(format!("Reloaded config, stopped {} connections and starting {} once they have finished",
+            stopped, waiting));
+    } else {
+        lines.push(format!("Reloaded config, started {} and stopped {} connections", started, stopped));
+    }
+    Ok(lines)
+}
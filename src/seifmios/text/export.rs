@@ -0,0 +1,133 @@
+extern crate rand;
+extern crate serde_json;
+use super::*;
+
+use super::super::cli::SocketLend;
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+#[derive(Clone, Copy, Debug)]
+pub enum MessageFormat {
+    /// Tab separated source, author, conversation and text, with tabs, line breaks and backslashes in them written
+    /// as `\t`, `\n`, `\r` and `\\`
+    Text,
+    /// Objects shaped like a `ChatMessage`, so `import jsonl` can read them back in
+    JsonLines,
+}
+
+impl MessageFormat {
+    pub fn parse(s: &str) -> Option<MessageFormat> {
+        match s {
+            "txt" => Some(MessageFormat::Text),
+            "jsonl" => Some(MessageFormat::JsonLines),
+            _ => None,
+        }
+    }
+}
+
+/// Which messages to export. Times are seconds since 1970.
+#[derive(Clone, Debug, Default)]
+pub struct MessageFilter {
+    pub source: Option<String>,
+    pub author: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct ExportedMessage {
+    source: String,
+    author: String,
+    conversation: usize,
+    timestamp: u64,
+    message: String,
+}
+
+impl<R: rand::Rng> Lexicon<R> {
+    /// Write every message that passes the filter to a file in the order they were told.
+    pub fn export_messages(&self, filename: String, format: MessageFormat, filter: MessageFilter, socket: &mut SocketLend) {
+        let file = match File::create(&filename) {
+            Ok(f) => f,
+            Err(e) => {
+                socket.error("io", &format!("Ignored: Unable to create file: {}", e));
+                return;
+            },
+        };
+        let mut out = BufWriter::new(file);
+        match self.write_messages(&mut out, format, &filter) {
+            Ok(count) => socket.msg(&format!("Wrote {} messages to `{}`", count, filename)),
+            Err(e) => socket.error("io", &format!("Ignored: Failed to write messages: {}", e)),
+        }
+    }
+
+    fn write_messages<W: Write>(&self, out: &mut W, format: MessageFormat, filter: &MessageFilter) -> io::Result<usize> {
+        let conversations = self.conversations.iter()
+            .cloned()
+            .enumerate()
+            .map(|(index, conversation)| (conversation, index))
+            .collect::<BTreeMap<_, _>>();
+
+        let mut count = 0;
+        for message in &self.messages {
+            let mb = message.borrow();
+            let ab = mb.author.borrow();
+            let source = ab.source.borrow().name.clone();
+            if filter.source.as_ref().map(|s| *s != source).unwrap_or(false) ||
+                filter.author.as_ref().map(|a| *a != ab.name).unwrap_or(false) ||
+                filter.since.map(|t| mb.time < t).unwrap_or(false) ||
+                filter.until.map(|t| mb.time >= t).unwrap_or(false) {
+                continue;
+            }
+
+            let exported = ExportedMessage{
+                source: source,
+                author: ab.name.clone(),
+                conversation: conversations.get(&mb.conversation).cloned().unwrap_or(0),
+                timestamp: mb.time,
+                message: mb.string(),
+            };
+            match format {
+                MessageFormat::Text => {
+                    try!(writeln!(out, "{}\t{}\t{}\t{}",
+                        escape(&exported.source), escape(&exported.author), exported.conversation,
+                        escape(&exported.message)));
+                },
+                MessageFormat::JsonLines => {
+                    let line = try!(serde_json::to_string(&exported)
+                        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{}", e))));
+                    try!(writeln!(out, "{}", line));
+                },
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Keep a column of the text format on one line and free of tabs
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::escape;
+
+    #[test]
+    fn escapes_columns() {
+        assert_eq!(escape("plain words"), "plain words");
+        assert_eq!(escape("a\tb\nc\r\\d"), "a\\tb\\nc\\r\\\\d");
+    }
+}
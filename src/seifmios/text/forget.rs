@@ -0,0 +1,173 @@
+extern crate rand;
+use super::*;
+
+use super::super::cli::SocketLend;
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+/// How many things said in each source are remembered
+const REMEMBERED: usize = 10;
+
+/// Add to the end of a source's history, dropping the oldest once there are too many
+fn push(history: &mut BTreeMap<SourceCell, VecDeque<Said>>, source: SourceCell, said: Said) {
+    let history = history.entry(source).or_insert_with(VecDeque::new);
+    history.push_back(said);
+    if history.len() > REMEMBERED {
+        history.pop_front();
+    }
+}
+
+/// Take the newest response with the given text out of a source's history
+fn take(history: &mut BTreeMap<SourceCell, VecDeque<Said>>, source: &SourceCell, text: &str) -> Option<Said> {
+    history.get_mut(source).and_then(|h| {
+        h.iter().rposition(|s| s.text == text).and_then(|i| h.remove(i))
+    })
+}
+
+impl<R: rand::Rng> Lexicon<R> {
+    /// Keep a response until whoever asked for it says whether it was sent. Whatever an adapter filters out or
+    /// fails to send never becomes something that can be forgotten.
+    pub fn hold(&mut self, source: SourceCell, text: String, instances: Vec<InstanceCell>) {
+        push(&mut self.unsent, source, Said{
+            text: text,
+            instances: instances,
+        });
+    }
+
+    /// Remember a response that was sent in a source so it can be forgotten later.
+    pub fn sent(&mut self, source: SourceCell, text: &str) {
+        if let Some(said) = take(&mut self.unsent, &source, text) {
+            push(&mut self.said, source, said);
+        }
+    }
+
+    /// Take back a response that was never sent because it didn't pass a filter.
+    pub fn retract(&mut self, source: SourceCell, text: &str) {
+        take(&mut self.unsent, &source, text);
+    }
+
+    /// Forget the last thing sent in a source so it is never said again. With `remove`, every message it was made
+    /// from is taken out of the lexicon too.
+    pub fn forget_last(&mut self, source: SourceCell, remove: bool, socket: &mut SocketLend) {
+        let said = match self.said.get_mut(&source).and_then(|s| s.pop_back()) {
+            Some(said) => said,
+            None => {
+                socket.error("not_found", "Ignored: Nothing has been said there to forget");
+                return;
+            },
+        };
+        socket.msg(&format!("Forgot `{}`", said.text));
+        self.blacklist.insert(said.text);
+        if remove {
+            let messages = said.instances.iter()
+                .map(|i| i.borrow().message.clone())
+                .collect::<BTreeSet<_>>();
+            let removed = messages.iter().filter(|m| self.remove_message(m)).count();
+            socket.msg(&format!("Removed {} messages it was made from", removed));
+        }
+    }
+
+    /// Take a message out of everything that refers to it, saying whether it was still there.
+    fn remove_message(&mut self, message: &MessageCell) -> bool {
+        let position = match self.messages.iter().position(|m| m == message) {
+            Some(position) => position,
+            None => return false,
+        };
+        self.messages.remove(position);
+        let mb = message.borrow();
+        for instance in &mb.instances {
+            let ib = instance.borrow();
+            let unused = {
+                let mut wb = ib.word.borrow_mut();
+                wb.instances.retain(|i| i != instance);
+                if wb.instances.is_empty() {
+                    Some(wb.name.clone())
+                } else {
+                    None
+                }
+            };
+            if let Some(name) = unused {
+                self.words.remove(&name);
+            }
+
+            let cocategories = {
+                let mut cb = ib.category.borrow_mut();
+                cb.instances.retain(|i| i != instance);
+                if cb.instances.is_empty() {
+                    // Nothing can be chosen from an empty category, so it shouldn't be anything's cocategory
+                    let pre = cb.precocategories.iter().cloned().collect::<Vec<_>>();
+                    let post = cb.postcocategories.iter().cloned().collect::<Vec<_>>();
+                    cb.precocategories.clear();
+                    cb.postcocategories.clear();
+                    Some((pre, post))
+                } else {
+                    None
+                }
+            };
+            if let Some((pre, post)) = cocategories {
+                for c in pre {
+                    c.borrow_mut().precocategories.remove(&ib.category);
+                }
+                for c in post {
+                    c.borrow_mut().postcocategories.remove(&ib.category);
+                }
+            }
+        }
+
+        // Keep the indexes of the rest of the conversation in order
+        let conversation = mb.conversation.clone();
+        let emptied = {
+            let mut cb = conversation.borrow_mut();
+            cb.messages.retain(|m| m != message);
+            for (index, m) in cb.messages.iter().enumerate() {
+                m.borrow_mut().index = index;
+            }
+            cb.messages.is_empty()
+        };
+        let ab = mb.author.borrow();
+        {
+            let mut sb = ab.source.borrow_mut();
+            sb.messages = sb.messages.saturating_sub(1);
+        }
+        // Nothing is left of the conversation, so the next message in its source starts a new one
+        if emptied {
+            self.conversations.retain(|c| *c != conversation);
+            if self.active_conversations.get(&ab.source) == Some(&conversation) {
+                self.active_conversations.remove(&ab.source);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rand::{Isaac64Rng, SeedableRng};
+    use super::super::Lexicon;
+
+    #[test]
+    fn removing_messages_drops_emptied_conversations() {
+        let mut lex = Lexicon::new(Isaac64Rng::from_seed(&[1, 2, 3, 4][..]));
+        let source = lex.source("#chat".to_string());
+        let author = lex.author(source.clone(), "alice".to_string());
+        lex.tell(source.clone(), author.clone(), "hello there".to_string());
+        lex.switch(source.clone());
+        lex.tell(source.clone(), author.clone(), "something else".to_string());
+        assert_eq!(lex.conversations.len(), 2);
+
+        let first = lex.messages[0].clone();
+        assert!(lex.remove_message(&first));
+        assert_eq!(lex.conversations.len(), 1);
+        assert!(lex.active_conversations.contains_key(&source));
+
+        let second = lex.messages[0].clone();
+        assert!(lex.remove_message(&second));
+        assert!(lex.conversations.is_empty());
+        assert!(!lex.active_conversations.contains_key(&source));
+
+        // Telling it something again starts a new conversation
+        lex.tell(source.clone(), author, "back again".to_string());
+        assert_eq!(lex.conversations.len(), 1);
+        assert!(!lex.remove_message(&first));
+    }
+}
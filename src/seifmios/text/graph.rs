@@ -0,0 +1,213 @@
+extern crate rand;
+extern crate itertools;
+use self::itertools::Itertools;
+use super::*;
+
+use super::super::cli::SocketLend;
+
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// How many of the most common words are put in a node label
+const LABEL_WORDS: usize = 5;
+
+#[derive(Clone, Copy, Debug)]
+pub enum GraphFormat {
+    Dot,
+    GraphMl,
+}
+
+impl GraphFormat {
+    pub fn parse(s: &str) -> Option<GraphFormat> {
+        match s {
+            "dot" => Some(GraphFormat::Dot),
+            "graphml" => Some(GraphFormat::GraphMl),
+            _ => None,
+        }
+    }
+}
+
+struct Node {
+    label: String,
+    size: usize,
+}
+
+#[derive(Clone, Copy)]
+enum EdgeKind {
+    Pre,
+    Post,
+}
+
+impl EdgeKind {
+    fn name(&self) -> &'static str {
+        match *self {
+            EdgeKind::Pre => "precocategory",
+            EdgeKind::Post => "postcocategory",
+        }
+    }
+}
+
+/// Make a label out of the most common words in a category
+fn label(cat: &Category) -> String {
+    let mut counts = BTreeMap::new();
+    for instance in &cat.instances {
+        *counts.entry(instance.borrow().word.borrow().name.clone()).or_insert(0usize) += 1;
+    }
+    let mut counts = counts.into_iter().collect_vec();
+    counts.sort_by(|a, b| (b.1, &a.0).cmp(&(a.1, &b.0)));
+    counts.into_iter().take(LABEL_WORDS).map(|(name, _)| name).join(" ")
+}
+
+/// Font size of a node, which grows slowly with the size of its category so the largest don't swamp the rest
+fn font_size(size: usize) -> f64 {
+    10.0 + 4.0 * (cmp::max(size, 1) as f64).log2()
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_xml(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+fn write_dot<W: Write>(out: &mut W, nodes: &[Node], edges: &[(usize, usize, EdgeKind)]) -> io::Result<()> {
+    try!(writeln!(out, "graph categories {{"));
+    for (id, node) in nodes.iter().enumerate() {
+        try!(writeln!(out, "    c{} [label=\"{} ({})\", fontsize={:.1}];",
+            id, escape_dot(&node.label), node.size, font_size(node.size)));
+    }
+    for &(a, b, kind) in edges {
+        let style = match kind {
+            EdgeKind::Pre => "color=blue",
+            EdgeKind::Post => "color=red, style=dashed",
+        };
+        try!(writeln!(out, "    c{} -- c{} [type={}, {}];", a, b, kind.name(), style));
+    }
+    writeln!(out, "}}")
+}
+
+fn write_graphml<W: Write>(out: &mut W, nodes: &[Node], edges: &[(usize, usize, EdgeKind)]) -> io::Result<()> {
+    try!(writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>"));
+    try!(writeln!(out, "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">"));
+    try!(writeln!(out, "  <key id=\"label\" for=\"node\" attr.name=\"label\" attr.type=\"string\"/>"));
+    try!(writeln!(out, "  <key id=\"size\" for=\"node\" attr.name=\"size\" attr.type=\"int\"/>"));
+    try!(writeln!(out, "  <key id=\"type\" for=\"edge\" attr.name=\"type\" attr.type=\"string\"/>"));
+    try!(writeln!(out, "  <graph id=\"categories\" edgedefault=\"undirected\">"));
+    for (id, node) in nodes.iter().enumerate() {
+        try!(writeln!(out, "    <node id=\"c{}\">", id));
+        try!(writeln!(out, "      <data key=\"label\">{}</data>", escape_xml(&node.label)));
+        try!(writeln!(out, "      <data key=\"size\">{}</data>", node.size));
+        try!(writeln!(out, "    </node>"));
+    }
+    for (id, &(a, b, kind)) in edges.iter().enumerate() {
+        try!(writeln!(out, "    <edge id=\"e{}\" source=\"c{}\" target=\"c{}\">", id, a, b));
+        try!(writeln!(out, "      <data key=\"type\">{}</data>", kind.name()));
+        try!(writeln!(out, "    </edge>"));
+    }
+    try!(writeln!(out, "  </graph>"));
+    writeln!(out, "</graphml>")
+}
+
+impl<R: rand::Rng> Lexicon<R> {
+    /// Write the category graph to a file. Only categories with at least `min_size` instances are included and,
+    /// if `around` is given, only those within that many cocategory hops of the given word.
+    pub fn export_graph(&self,
+        filename: String,
+        format: GraphFormat,
+        min_size: usize,
+        around: Option<(String, usize)>,
+        socket: &mut SocketLend,
+    ) {
+        let categories = match around {
+            Some((word, hops)) => {
+                let w = match self.words.get(&word) {
+                    Some(w) => w.clone(),
+                    None => {
+                        socket.error("not_found", &format!("Ignored: Word \"{}\" couldn't be found", word));
+                        return;
+                    },
+                };
+                let mut set = w.borrow().instances.iter()
+                    .map(|instance| instance.borrow().category.clone())
+                    .collect::<BTreeSet<_>>();
+                // Walk outwards through the cocategories
+                let mut frontier = set.iter().cloned().collect_vec();
+                for _ in 0..hops {
+                    let mut next = Vec::new();
+                    for cat in &frontier {
+                        let cb = cat.borrow();
+                        for cocategory in cb.precocategories.iter().chain(cb.postcocategories.iter()) {
+                            if set.insert(cocategory.clone()) {
+                                next.push(cocategory.clone());
+                            }
+                        }
+                    }
+                    frontier = next;
+                }
+                set
+            },
+            None => {
+                let mut set = BTreeSet::new();
+                for message in &self.messages {
+                    for instance in &message.borrow().instances {
+                        set.insert(instance.borrow().category.clone());
+                    }
+                }
+                set
+            },
+        };
+
+        // Give every category that passes the filter a node ID
+        let mut ids = BTreeMap::new();
+        let mut nodes = Vec::new();
+        for cat in &categories {
+            let cb = cat.borrow();
+            if cb.instances.len() >= min_size {
+                ids.insert(cat.clone(), nodes.len());
+                nodes.push(Node{
+                    label: label(&cb),
+                    size: cb.instances.len(),
+                });
+            }
+        }
+
+        // Cocategories are symmetric, so each edge is only written from its lower ID
+        let mut edges = Vec::new();
+        for (cat, &id) in &ids {
+            let cb = cat.borrow();
+            for (cocategories, kind) in vec![(&cb.precocategories, EdgeKind::Pre), (&cb.postcocategories, EdgeKind::Post)] {
+                for cocategory in cocategories {
+                    if let Some(&other) = ids.get(cocategory) {
+                        if id < other {
+                            edges.push((id, other, kind));
+                        }
+                    }
+                }
+            }
+        }
+
+        let file = match File::create(&filename) {
+            Ok(f) => f,
+            Err(e) => {
+                socket.error("io", &format!("Ignored: Unable to create file: {}", e));
+                return;
+            },
+        };
+        let mut out = BufWriter::new(file);
+        let result = match format {
+            GraphFormat::Dot => write_dot(&mut out, &nodes, &edges),
+            GraphFormat::GraphMl => write_graphml(&mut out, &nodes, &edges),
+        };
+        match result {
+            Ok(_) => socket.msg(&format!("Wrote {} categories and {} edges to `{}`", nodes.len(), edges.len(), filename)),
+            Err(e) => socket.error("io", &format!("Ignored: Failed to write graph: {}", e)),
+        }
+    }
+}
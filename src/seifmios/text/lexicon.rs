@@ -8,6 +8,7 @@ use super::super::cli::SocketLend;
 
 use std::collections::{BTreeMap, BTreeSet};
 use std::collections::btree_map::Entry;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 const RATIO_TO_COCATEGORIZE: f64 = 0.4;
 const COCATEGORY_TRAVEL_DISTANCE: i32 = 0;
@@ -16,6 +17,8 @@ const FORWARD_EDGE_DISTANCE: usize = 1;
 const BACKWARD_EDGE_DISTANCE: usize = 1;
 const FORWARD_WORD_DISTANCE: usize = 1;
 const BACKWARD_WORD_DISTANCE: usize = 1;
+/// How many times to try saying something that wasn't forgotten before giving up
+const RESPOND_ATTEMPTS: usize = 8;
 
 impl<R: rand::Rng> Lexicon<R> {
     /// Make a new lexion. It needs its own Rng for internal purposes of learning.
@@ -34,6 +37,9 @@ impl<R: rand::Rng> Lexicon<R> {
             conversations: Default::default(),
             messages: Default::default(),
             active_conversations: Default::default(),
+            said: Default::default(),
+            unsent: Default::default(),
+            blacklist: Default::default(),
         }
     }
 
@@ -66,6 +72,12 @@ impl<R: rand::Rng> Lexicon<R> {
 
     /// Tell a message to the lexicon and potentially get a response back.
     pub fn tell(&mut self, source: SourceCell, author: AuthorCell, content: String) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        self.tell_at(source, author, content, now);
+    }
+
+    /// Tell a message that was said at `time` seconds since 1970, such as one being imported.
+    pub fn tell_at(&mut self, source: SourceCell, author: AuthorCell, content: String, time: u64) {
         let conversation = match self.active_conversations.entry(source.clone()) {
             Entry::Vacant(v) => {
                 let c = wrap(Conversation{
@@ -83,6 +95,7 @@ impl<R: rand::Rng> Lexicon<R> {
 
         let message = wrap(Message{
             last_checked_at: 0,
+            time: time,
             author: author.clone(),
             conversation: conversation.clone(),
             index: conversation.borrow().messages.len(),
@@ -154,8 +167,24 @@ impl<R: rand::Rng> Lexicon<R> {
         self.conversations.push(conversation.clone());
     }
 
-    /// Say something based on the conversation context
+    /// Say something based on the conversation context, giving back what it was originally and what it became.
+    /// Nothing that was forgotten is said again, and once `sent` confirms it was said it can be forgotten.
     pub fn respond(&mut self, source: SourceCell) -> Option<(String, String)> {
+        for _ in 0..RESPOND_ATTEMPTS {
+            let (original, response, instances) = match self.compose(source.clone()) {
+                Some(composed) => composed,
+                None => return None,
+            };
+            if !self.blacklist.contains(&response) {
+                self.hold(source, response.clone(), instances);
+                return Some((original, response));
+            }
+        }
+        None
+    }
+
+    /// Make something to say, along with the instances it was made of.
+    fn compose(&mut self, source: SourceCell) -> Option<(String, String, Vec<InstanceCell>)> {
         use std::collections::VecDeque;
         let base = match self.rng.choose(&self.messages[..]) {
             Some(m) => m.clone(),
@@ -264,6 +293,26 @@ impl<R: rand::Rng> Lexicon<R> {
             }
         }
 
+        let travelled = instances.iter()
+            .cloned()
+            .enumerate()
+            .map(|(index, mut instance)| {
+                if index != orig_index {
+                    for _ in 0..self.cocategory_travel_distance {
+                        instance = {
+                            let b = instance.borrow();
+                            if self.rng.gen_range(0, 2) == 0 {
+                                backward_instance_chooser(&b.category.borrow(), &mut self.rng)
+                            } else {
+                                forward_instance_chooser(&b.category.borrow(), &mut self.rng)
+                            }
+                        };
+                    }
+                }
+                instance
+            })
+            .collect_vec();
+
         Some((
             instances.iter()
                 .map(|instance| {
@@ -272,30 +321,14 @@ impl<R: rand::Rng> Lexicon<R> {
                     word.name.clone()
                 })
                 .join(" "),
-            instances.iter()
-                .cloned()
-                .enumerate()
-                .map(|(index, mut instance)| {
-                    if index != orig_index {
-                        for _ in 0..self.cocategory_travel_distance {
-                            instance = {
-                                let b = instance.borrow();
-                                if self.rng.gen_range(0, 2) == 0 {
-                                    backward_instance_chooser(&b.category.borrow(), &mut self.rng)
-                                } else {
-                                    forward_instance_chooser(&b.category.borrow(), &mut self.rng)
-                                }
-                            };
-                        }
-                    }
-                    instance
-                })
+            travelled.iter()
                 .map(|instance| {
                     let ins = instance.borrow();
                     let word = ins.word.borrow();
                     word.name.clone()
                 })
-                .join(" ")
+                .join(" "),
+            travelled,
         ))
     }
 
@@ -411,6 +444,38 @@ impl<R: rand::Rng> Lexicon<R> {
         }
     }
 
+    /// Give the sizes of everything that has been learned
+    pub fn stats(&self, socket: &mut SocketLend) {
+        let mut categories = BTreeSet::new();
+        let mut instances = 0;
+        for message in &self.messages {
+            for instance in &message.borrow().instances {
+                categories.insert(instance.borrow().category.clone());
+                instances += 1;
+            }
+        }
+        let multiple = categories.iter().filter(|c| c.borrow().instances.len() != 1).count();
+
+        socket.msg(&format!("words: {}", self.words.len()));
+        socket.msg(&format!("instances: {}", instances));
+        socket.msg(&format!("messages: {}", self.messages.len()));
+        socket.msg(&format!("conversations: {}", self.conversations.len()));
+        socket.msg(&format!("sources: {}", self.sources.len()));
+        socket.msg(&format!("categories: {}", categories.len()));
+        socket.msg(&format!("multiple categories: {}", multiple));
+    }
+
+    /// Show every tuning value next to its default.
+    pub fn settings(&self, socket: &mut SocketLend) {
+        socket.msg(&format!("cc_ratio: {} (default {})", self.cocategorization_ratio, RATIO_TO_COCATEGORIZE));
+        socket.msg(&format!("cc_travel: {} (default {})", self.cocategory_travel_distance, COCATEGORY_TRAVEL_DISTANCE));
+        socket.msg(&format!("cc_mag: {} (default {})", self.cocategorize_magnitude, COCATEGORIZE_MAGNITUDE));
+        socket.msg(&format!("fw_edge: {} (default {})", self.forward_edge_distance, FORWARD_EDGE_DISTANCE));
+        socket.msg(&format!("bw_edge: {} (default {})", self.backward_edge_distance, BACKWARD_EDGE_DISTANCE));
+        socket.msg(&format!("fw_word: {} (default {})", self.forward_word_distance, FORWARD_WORD_DISTANCE));
+        socket.msg(&format!("bw_word: {} (default {})", self.backward_word_distance, BACKWARD_WORD_DISTANCE));
+    }
+
     pub fn find_relation(&self, words: (String, String), socket: &mut SocketLend) {
         let wls = (self.words.get(&words.0), self.words.get(&words.1));
         match wls {
@@ -463,14 +528,96 @@ impl<R: rand::Rng> Lexicon<R> {
                 }
             },
             (Some(_), None) => {
-                socket.msg(&format!("Ignored: Word \"{}\" coldn't be found", words.1));
+                socket.error("not_found", &format!("Ignored: Word \"{}\" coldn't be found", words.1));
             },
             (None, Some(_)) => {
-                socket.msg(&format!("Ignored: Word \"{}\" coldn't be found", words.0));
+                socket.error("not_found", &format!("Ignored: Word \"{}\" coldn't be found", words.0));
             },
             (None, None) => {
-                socket.msg("Ignored: Neither word could be found");
+                socket.error("not_found", "Ignored: Neither word could be found");
+            },
+        }
+    }
+
+    /// List every category a word is in, the words sharing those categories ranked by co-membership, and the
+    /// words reachable through cocategories within `hops` steps.
+    pub fn find_word(&self, word: String, hops: usize, socket: &mut SocketLend) {
+        let w = match self.words.get(&word) {
+            Some(w) => w.clone(),
+            None => {
+                socket.error("not_found", &format!("Ignored: Word \"{}\" couldn't be found", word));
+                return;
             },
+        };
+
+        // Get the distinct categories that the instances of this word are in
+        let categories = w.borrow().instances.iter()
+            .map(|instance| instance.borrow().category.clone())
+            .collect::<BTreeSet<_>>();
+
+        // Count how many times each other word shares a category with this word
+        let mut totals = BTreeMap::new();
+        for cat in &categories {
+            let cb = cat.borrow();
+            let mut counts = BTreeMap::new();
+            for instance in &cb.instances {
+                let ib = instance.borrow();
+                if ib.word != w {
+                    *counts.entry(ib.word.borrow().name.clone()).or_insert(0usize) += 1;
+                }
+            }
+            socket.msg(&format!("Category ({} instances):", cb.instances.len()));
+            let mut counts = counts.into_iter().collect_vec();
+            counts.sort_by(|a, b| (b.1, &a.0).cmp(&(a.1, &b.0)));
+            for (name, count) in counts {
+                socket.msg(&format!("\t{} x{}", name, count));
+                *totals.entry(name).or_insert(0usize) += count;
+            }
+        }
+
+        socket.msg("Nearest words:");
+        let mut totals = totals.into_iter().collect_vec();
+        totals.sort_by(|a, b| (b.1, &a.0).cmp(&(a.1, &b.0)));
+        for &(ref name, count) in &totals {
+            socket.msg(&format!("\t{} x{}", name, count));
+        }
+
+        // Walk outwards through the pre and post cocategories one hop at a time
+        let mut seen_words = totals.into_iter().map(|(name, _)| name).collect::<BTreeSet<_>>();
+        seen_words.insert(word);
+        let mut visited = categories.clone();
+        let mut frontier = categories.into_iter().collect_vec();
+        for hop in 1..hops + 1 {
+            let mut next = Vec::new();
+            for cat in &frontier {
+                let cb = cat.borrow();
+                for cocategory in cb.precocategories.iter().chain(cb.postcocategories.iter()) {
+                    if visited.insert(cocategory.clone()) {
+                        next.push(cocategory.clone());
+                    }
+                }
+            }
+
+            let mut found = BTreeSet::new();
+            for cat in &next {
+                for instance in &cat.borrow().instances {
+                    let name = instance.borrow().word.borrow().name.clone();
+                    if seen_words.insert(name.clone()) {
+                        found.insert(name);
+                    }
+                }
+            }
+            if !found.is_empty() {
+                socket.msg(&format!("Within {} cocategory hop(s):", hop));
+                for name in found {
+                    socket.msg(&format!("\t{}", name));
+                }
+            }
+
+            if next.is_empty() {
+                break;
+            }
+            frontier = next;
         }
     }
 }
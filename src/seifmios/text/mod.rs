@@ -14,8 +14,15 @@ mod conversation;
 mod author;
 mod source;
 mod word;
+mod graph;
+mod export;
+mod snapshot;
+mod forget;
 
-use std::collections::{BTreeMap, BTreeSet};
+pub use self::graph::GraphFormat;
+pub use self::export::{MessageFilter, MessageFormat};
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
 
 pub type WordCell = SCell<Word>;
 pub type AuthorCell = SCell<Author>;
@@ -55,6 +62,12 @@ pub struct Lexicon<R: Rng> {
     messages: Vec<MessageCell>,
 
     active_conversations: BTreeMap<SourceCell, ConversationCell>,
+    /// Last few things said in each source, newest last, so they can be forgotten
+    said: BTreeMap<SourceCell, VecDeque<Said>>,
+    /// Last few responses in each source that haven't been confirmed as sent, newest last
+    unsent: BTreeMap<SourceCell, VecDeque<Said>>,
+    /// Things that were forgotten and won't be said again
+    blacklist: BTreeSet<String>,
 }
 
 #[derive(Deserialize, Serialize)]
@@ -64,14 +77,22 @@ pub struct SerialLexicon {
     conversations: Vec<u64>,
     messages: Vec<u64>,
 
-    // Maps to look things up by unique ID
-    conversation_map: BTreeMap<u64, SerialConversation>,
-    author_map: BTreeMap<u64, SerialAuthor>,
-    source_map: BTreeMap<u64, SerialSource>,
-    word_instance_map: BTreeMap<u64, SerialWordInstance>,
-    message_map: BTreeMap<u64, SerialMessage>,
-    category_map: BTreeMap<u64, SerialCategory>,
-    word_map: BTreeMap<u64, SerialWord>,
+    // Maps to look things up by unique ID, which is the index
+    conversation_map: Vec<SerialConversation>,
+    author_map: Vec<SerialAuthor>,
+    source_map: Vec<SerialSource>,
+    word_instance_map: Vec<SerialWordInstance>,
+    message_map: Vec<SerialMessage>,
+    category_map: Vec<SerialCategory>,
+    word_map: Vec<SerialWord>,
+    #[serde(default)]
+    blacklist: Vec<String>,
+}
+
+/// Something the lexicon said, with the instances it was made of
+pub struct Said {
+    text: String,
+    instances: Vec<InstanceCell>,
 }
 
 pub struct Conversation {
@@ -126,6 +147,8 @@ struct SerialWordInstance {
 
 pub struct Message {
     last_checked_at: usize,
+    // Seconds since 1970 when the message was said
+    time: u64,
     author: AuthorCell,
     conversation: ConversationCell,
     index: usize,
@@ -134,6 +157,7 @@ pub struct Message {
 
 #[derive(Deserialize, Serialize)]
 struct SerialMessage {
+    time: u64,
     author: u64,
     conversation: u64,
     index: u64,
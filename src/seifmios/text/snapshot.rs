@@ -0,0 +1,246 @@
+extern crate rand;
+extern crate serde_json;
+extern crate itertools;
+use self::itertools::Itertools;
+use super::*;
+use super::wrap;
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+/// Give every item a unique ID, which is its index
+fn ids<T: Ord + Clone>(items: &[T]) -> BTreeMap<T, u64> {
+    items.iter().cloned().zip(0..).collect()
+}
+
+/// Look up an item by the ID it had in a snapshot
+fn get<T: Clone>(items: &[T], id: u64, what: &str) -> Result<T, String> {
+    items.get(id as usize).cloned().ok_or_else(|| format!("Snapshot refers to missing {} {}", what, id))
+}
+
+impl<R: rand::Rng> Lexicon<R> {
+    /// Turn everything that has been learned into a form that can be saved.
+    pub fn serialize(&self) -> SerialLexicon {
+        let sources = self.sources.values().cloned().collect_vec();
+        let authors = sources.iter()
+            .flat_map(|s| s.borrow().authors.values().cloned().collect_vec())
+            .collect_vec();
+        let words = self.words.values().cloned().collect_vec();
+        let instances = self.messages.iter()
+            .flat_map(|m| m.borrow().instances.clone())
+            .collect_vec();
+        let categories = instances.iter()
+            .map(|i| i.borrow().category.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect_vec();
+
+        let (source_ids, author_ids, word_ids, instance_ids, category_ids) =
+            (ids(&sources), ids(&authors), ids(&words), ids(&instances), ids(&categories));
+        let conversation_ids = ids(&self.conversations);
+        let message_ids = ids(&self.messages);
+        let cocategory_ids = |cocategories: &BTreeSet<CategoryCell>| {
+            // Merged categories are emptied of cocategories, so every cocategory should still be in use
+            cocategories.iter().filter_map(|c| category_ids.get(c).cloned()).collect_vec()
+        };
+
+        SerialLexicon{
+            words: self.words.iter().map(|(name, w)| (name.clone(), word_ids[w])).collect(),
+            sources: self.sources.iter().map(|(name, s)| (name.clone(), source_ids[s])).collect(),
+            conversations: self.conversations.iter().map(|c| conversation_ids[c]).collect(),
+            messages: self.messages.iter().map(|m| message_ids[m]).collect(),
+
+            conversation_map: self.conversations.iter().map(|c| {
+                let cb = c.borrow();
+                SerialConversation{
+                    source: source_ids[&cb.source],
+                    messages: cb.messages.iter().map(|m| message_ids[m]).collect(),
+                }
+            }).collect(),
+            author_map: authors.iter().map(|a| {
+                let ab = a.borrow();
+                SerialAuthor{
+                    source: source_ids[&ab.source],
+                    name: ab.name.clone(),
+                }
+            }).collect(),
+            source_map: sources.iter().map(|s| {
+                let sb = s.borrow();
+                SerialSource{
+                    name: sb.name.clone(),
+                    messages: sb.messages,
+                    authors: sb.authors.iter().map(|(name, a)| (name.clone(), author_ids[a])).collect(),
+                }
+            }).collect(),
+            word_instance_map: instances.iter().map(|i| {
+                let ib = i.borrow();
+                SerialWordInstance{
+                    word: word_ids[&ib.word],
+                    category: category_ids[&ib.category],
+                    message: message_ids[&ib.message],
+                    index: ib.index as u64,
+                }
+            }).collect(),
+            message_map: self.messages.iter().map(|m| {
+                let mb = m.borrow();
+                SerialMessage{
+                    time: mb.time,
+                    author: author_ids[&mb.author],
+                    conversation: conversation_ids[&mb.conversation],
+                    index: mb.index as u64,
+                    instances: mb.instances.iter().map(|i| instance_ids[i]).collect(),
+                }
+            }).collect(),
+            category_map: categories.iter().map(|c| {
+                let cb = c.borrow();
+                SerialCategory{
+                    instances: cb.instances.iter().map(|i| instance_ids[i]).collect(),
+                    precocategories: cocategory_ids(&cb.precocategories),
+                    postcocategories: cocategory_ids(&cb.postcocategories),
+                }
+            }).collect(),
+            word_map: words.iter().map(|w| {
+                let wb = w.borrow();
+                SerialWord{
+                    name: wb.name.clone(),
+                    instances: wb.instances.iter().map(|i| instance_ids[i]).collect(),
+                }
+            }).collect(),
+            blacklist: self.blacklist.iter().cloned().collect(),
+        }
+    }
+
+    /// Replace everything that has been learned with what was saved. The tuning values are left alone, and nothing
+    /// changes if the snapshot is inconsistent.
+    pub fn restore(&mut self, serial: SerialLexicon) -> Result<(), String> {
+        // Make every item first so they can refer to each other
+        let sources = serial.source_map.iter().map(|s| wrap(Source{
+            name: s.name.clone(),
+            messages: s.messages,
+            authors: BTreeMap::new(),
+        })).collect_vec();
+        let authors = try!(serial.author_map.iter().map(|a| -> Result<_, String> {
+            Ok(wrap(Author{
+                source: try!(get(&sources, a.source, "source")),
+                name: a.name.clone(),
+            }))
+        }).collect::<Result<Vec<_>, _>>());
+        let words = serial.word_map.iter().map(|w| wrap(Word{
+            name: w.name.clone(),
+            instances: Vec::new(),
+        })).collect_vec();
+        let conversations = try!(serial.conversation_map.iter().map(|c| -> Result<_, String> {
+            Ok(wrap(Conversation{
+                source: try!(get(&sources, c.source, "source")),
+                messages: Vec::new(),
+            }))
+        }).collect::<Result<Vec<_>, _>>());
+        let categories = serial.category_map.iter().map(|_| wrap(Category::default())).collect_vec();
+        let messages = try!(serial.message_map.iter().map(|m| -> Result<_, String> {
+            Ok(wrap(Message{
+                last_checked_at: 0,
+                time: m.time,
+                author: try!(get(&authors, m.author, "author")),
+                conversation: try!(get(&conversations, m.conversation, "conversation")),
+                index: m.index as usize,
+                instances: Vec::new(),
+            }))
+        }).collect::<Result<Vec<_>, _>>());
+        let instances = try!(serial.word_instance_map.iter().map(|i| -> Result<_, String> {
+            Ok(wrap(WordInstance{
+                word: try!(get(&words, i.word, "word")),
+                category: try!(get(&categories, i.category, "category")),
+                message: try!(get(&messages, i.message, "message")),
+                index: i.index as usize,
+            }))
+        }).collect::<Result<Vec<_>, _>>());
+
+        // Then link them back together
+        for (s, source) in serial.source_map.iter().zip(&sources) {
+            for (name, &id) in &s.authors {
+                source.borrow_mut().authors.insert(name.clone(), try!(get(&authors, id, "author")));
+            }
+        }
+        for (c, conversation) in serial.conversation_map.iter().zip(&conversations) {
+            for &id in &c.messages {
+                conversation.borrow_mut().messages.push(try!(get(&messages, id, "message")));
+            }
+        }
+        for (m, message) in serial.message_map.iter().zip(&messages) {
+            for &id in &m.instances {
+                message.borrow_mut().instances.push(try!(get(&instances, id, "instance")));
+            }
+        }
+        for (w, word) in serial.word_map.iter().zip(&words) {
+            for &id in &w.instances {
+                word.borrow_mut().instances.push(try!(get(&instances, id, "instance")));
+            }
+        }
+        for (c, category) in serial.category_map.iter().zip(&categories) {
+            let mut cb = category.borrow_mut();
+            for &id in &c.instances {
+                cb.instances.push(try!(get(&instances, id, "instance")));
+            }
+            for &id in &c.precocategories {
+                cb.precocategories.insert(try!(get(&categories, id, "category")));
+            }
+            for &id in &c.postcocategories {
+                cb.postcocategories.insert(try!(get(&categories, id, "category")));
+            }
+        }
+
+        let mut word_names = BTreeMap::new();
+        for (name, &id) in &serial.words {
+            word_names.insert(name.clone(), try!(get(&words, id, "word")));
+        }
+        let mut source_names = BTreeMap::new();
+        for (name, &id) in &serial.sources {
+            source_names.insert(name.clone(), try!(get(&sources, id, "source")));
+        }
+        let conversations = try!(serial.conversations.iter()
+            .map(|&id| get(&conversations, id, "conversation"))
+            .collect::<Result<Vec<_>, _>>());
+        let messages = try!(serial.messages.iter()
+            .map(|&id| get(&messages, id, "message"))
+            .collect::<Result<Vec<_>, _>>());
+
+        self.words = word_names;
+        self.sources = source_names;
+        self.conversations = conversations;
+        self.messages = messages;
+        self.blacklist = serial.blacklist.into_iter().collect();
+        // Every source starts a new conversation, and what was said was made of the old lexicon
+        self.active_conversations.clear();
+        self.said.clear();
+        self.unsent.clear();
+        Ok(())
+    }
+
+    /// Save a snapshot of everything that has been learned. It is written next to the file first so a failure
+    /// doesn't clobber the last good snapshot.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let temporary = path.with_extension("tmp");
+        {
+            let file = try!(File::create(&temporary).map_err(|e| format!("Unable to create snapshot: {}", e)));
+            let mut writer = BufWriter::new(file);
+            try!(serde_json::to_writer(&mut writer, &self.serialize())
+                .map_err(|e| format!("Unable to write snapshot: {}", e)));
+            // Dropping the writer would flush it and throw away any error, so the rename could replace the last
+            // good snapshot with a truncated one
+            let file = try!(writer.into_inner().map_err(|e| format!("Unable to write snapshot: {}", e.error())));
+            try!(file.sync_all().map_err(|e| format!("Unable to write snapshot: {}", e)));
+        }
+        fs::rename(&temporary, path).map_err(|e| format!("Unable to replace snapshot: {}", e))
+    }
+
+    /// Load a snapshot made by `save`.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let file = try!(File::open(path).map_err(|e| format!("Unable to open snapshot: {}", e)));
+        let serial = try!(serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| format!("Unable to read snapshot: {}", e)));
+        self.restore(serial)
+    }
+}
@@ -1 +1,59 @@
+use std::env;
+
+/// Endpoint used when nothing else is given
 pub const PATH: &'static str = "ipc:///tmp/seifmios_cli_commands.ipc";
+/// Environment variable holding the endpoint, such as `tcp://127.0.0.1:2934`
+pub const ENDPOINT_VAR: &'static str = "SEIFMIOS_ENDPOINT";
+
+/// Environment variable holding the token climios sends with every command
+pub const TOKEN_VAR: &'static str = "SEIFMIOS_TOKEN";
+
+/// Pick the endpoint from the command line flag if there is one, then the environment, then the configured one,
+/// then the default.
+pub fn endpoint(flag: Option<String>, configured: Option<String>) -> String {
+    flag.or_else(|| env::var(ENDPOINT_VAR).ok()).or(configured).unwrap_or_else(|| PATH.to_string())
+}
+
+/// A part of the response to a command
+pub const STATUS_OK: &'static str = "ok";
+/// A part of the response saying what went wrong with a command
+pub const STATUS_ERROR: &'static str = "error";
+/// The last message of every response
+pub const STATUS_END: &'static str = "end";
+
+/// Every message sent back over the socket is one of these encoded as JSON.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Reply {
+    pub status: String,
+    /// Machine-readable reason for errors, such as `usage` or `not_found`. The end of a response carries the code
+    /// of the first error in it, if there was one.
+    pub code: Option<String>,
+    /// What a person would be shown
+    pub payload: Option<String>,
+}
+
+impl Reply {
+    pub fn ok(payload: &str) -> Reply {
+        Reply{
+            status: STATUS_OK.to_string(),
+            code: None,
+            payload: Some(payload.to_string()),
+        }
+    }
+
+    pub fn error(code: &str, payload: &str) -> Reply {
+        Reply{
+            status: STATUS_ERROR.to_string(),
+            code: Some(code.to_string()),
+            payload: Some(payload.to_string()),
+        }
+    }
+
+    pub fn end(code: Option<String>) -> Reply {
+        Reply{
+            status: STATUS_END.to_string(),
+            code: code,
+            payload: None,
+        }
+    }
+}